@@ -0,0 +1,39 @@
+#!/bin/sh
+# Regenerates the KEY_CODES table in src/key_codes.rs from the kernel's
+# input-event-codes.h, e.g. after a kernel adds keys:
+#
+#     scripts/gen-key-codes.sh [/usr/include/linux/input-event-codes.h]
+set -eu
+
+header=${1:-/usr/include/linux/input-event-codes.h}
+target=$(dirname "$0")/../src/key_codes.rs
+
+table=$(awk '
+    # Not every awk has strtonum
+    function hex(digits,    i, n) {
+        n = 0
+        for (i = 3; i <= length(digits); i++)
+            n = n * 16 + index("0123456789abcdef", tolower(substr(digits, i, 1))) - 1
+        return n
+    }
+    $1 == "#define" && $2 ~ /^KEY_/ {
+        name = $2
+        value = $3
+        # Markers rather than keys
+        if (name == "KEY_MIN_INTERESTING" || name == "KEY_MAX" || name == "KEY_CNT")
+            next
+        if (value ~ /^KEY_/)
+            value = codes[value]
+        else if (value ~ /^0x/)
+            value = hex(value)
+        codes[name] = value
+        printf "    (\"%s\", %d),\n", name, value
+    }
+' "$header")
+
+awk -v table="$table" '
+    /^pub const KEY_CODES/ { print; print table; skipping = 1; next }
+    skipping && /^\];/ { skipping = 0 }
+    !skipping { print }
+' "$target" > "$target.new"
+mv "$target.new" "$target"
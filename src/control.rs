@@ -0,0 +1,347 @@
+//! A Unix socket for querying and controlling the running daemon.
+//!
+//! Clients send one JSON object per line and get one JSON object back per
+//! line. Every request has a `command`:
+//!
+//! - `{"command": "status"}`
+//! - `{"command": "mappings"}`, optionally with a `"profile"`
+//! - `{"command": "switch_profile", "profile": "wow"}` (or `next`/`previous`)
+//! - `{"command": "reload"}`
+//! - `{"command": "pause"}` and `{"command": "resume"}`
+//! - `{"command": "stop"}`
+//!
+//! Replies carry `"ok": true` and any requested data, or `"ok": false` and
+//! an `"error"` message.
+
+use crate::key_map::{ControlSettings, ProfileSwitch};
+use crate::poll;
+use crate::reload::{self, SharedKeyMapper};
+use serde_json::{json, Map, Value};
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Longest request line a client may send, in bytes.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+/// How long a client may take to send its next request before it is
+/// disconnected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most clients served at once; more are turned away.
+const MAX_CLIENTS: usize = 8;
+
+/// What the control socket acts on.
+struct Control {
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+    config_path: Option<PathBuf>,
+    clients: AtomicUsize,
+}
+
+/// Creates the control socket and starts a thread serving it.
+///
+/// The socket is only usable by root, or by root and `group` if the
+/// settings name one. `config_path` is the file the `reload` command reads.
+/// The thread stops within a second of `running` being cleared and removes
+/// the socket file; join it before exiting so it gets the chance.
+pub fn serve(
+    settings: &ControlSettings,
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+    config_path: Option<&str>,
+) -> io::Result<JoinHandle<()>> {
+    let path = Path::new(settings.socket());
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another daemon", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    match settings.group() {
+        Some(group) => {
+            let gid = group_id(group)?;
+            let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+            if unsafe { libc::chown(c_path.as_ptr(), u32::MAX, gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        }
+        None => fs::set_permissions(path, fs::Permissions::from_mode(0o600))?,
+    }
+
+    // Nonblocking, so a client that gives up between the poll and the
+    // accept can't leave the thread stuck past shutdown
+    listener.set_nonblocking(true)?;
+    let path = path.to_path_buf();
+    let control = Arc::new(Control {
+        key_mapper,
+        running,
+        config_path: config_path.map(PathBuf::from),
+        clients: AtomicUsize::new(0),
+    });
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            while control.running.load(Ordering::SeqCst) {
+                if let Err(e) = poll::wait(&[listener.as_raw_fd()], None, Some(poll::RUNNING_CHECK)) {
+                    eprintln!("Control socket error: {}", e);
+                    break;
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => control.accept(stream),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => eprintln!("Control socket error: {}", e),
+                }
+            }
+            let _ = fs::remove_file(&path);
+        })
+}
+
+/// Sends one request to the daemon's control socket and returns the reply.
+pub fn request(socket: &str, request: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    writeln!(stream, "{}", request)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut reply = String::new();
+    BufReader::new(stream).read_line(&mut reply)?;
+    Ok(reply.trim_end().to_string())
+}
+
+impl Control {
+    /// Serves a new client on its own thread, unless too many already are.
+    fn accept(self: &Arc<Self>, stream: UnixStream) {
+        if self.clients.fetch_add(1, Ordering::SeqCst) >= MAX_CLIENTS {
+            self.clients.fetch_sub(1, Ordering::SeqCst);
+            let mut stream = stream;
+            let _ = writeln!(stream, "{}", error("Too many clients".to_string()));
+            return;
+        }
+        let control = self.clone();
+        let spawned = thread::Builder::new()
+            .name("control-client".to_string())
+            .spawn(move || {
+                match control.serve_client(stream) {
+                    // The client went quiet for CLIENT_TIMEOUT
+                    Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {}
+                    Err(e) => eprintln!("Control client error: {}", e),
+                    Ok(()) => {}
+                }
+                control.clients.fetch_sub(1, Ordering::SeqCst);
+            });
+        if let Err(e) = spawned {
+            self.clients.fetch_sub(1, Ordering::SeqCst);
+            eprintln!("Could not serve control client: {}", e);
+        }
+    }
+
+    fn serve_client(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        loop {
+            // Reading at most one byte more than a request may have tells
+            // an overlong line from one that fits
+            let mut line = Vec::new();
+            if (&mut reader).take(MAX_REQUEST_LEN + 1).read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if line.len() as u64 > MAX_REQUEST_LEN {
+                let message = format!("Requests are limited to {} bytes", MAX_REQUEST_LEN);
+                writeln!(writer, "{}", error(message))?;
+                return Ok(());
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            let reply = match serde_json::from_slice(&line) {
+                Ok(request) => self.handle(&request),
+                Err(e) => error(format!("Invalid request: {}", e)),
+            };
+            writeln!(writer, "{}", reply)?;
+        }
+    }
+
+    fn handle(&self, request: &Value) -> Value {
+        let Some(command) = request.get("command").and_then(Value::as_str) else {
+            return error("Request needs a `command`".to_string());
+        };
+        let profile = request.get("profile").and_then(Value::as_str);
+
+        match command {
+            "status" => self.status(),
+            "mappings" => self.mappings(profile),
+            "switch_profile" => match profile {
+                Some(name) => {
+                    let switch = ProfileSwitch::from_name(name);
+                    match self.key_mapper.switch_profile(&switch) {
+                        Ok(()) => self.status(),
+                        Err(e) => error(e),
+                    }
+                }
+                None => error("`switch_profile` needs a `profile`".to_string()),
+            },
+            "reload" => match &self.config_path {
+                Some(path) => match reload::reload(path, &self.key_mapper) {
+                    Ok(()) => self.status(),
+                    Err(e) => error(e),
+                },
+                None => error("Running without a config file".to_string()),
+            },
+            "pause" | "resume" => {
+                self.key_mapper.set_paused(command == "pause");
+                self.status()
+            }
+            "stop" => {
+                println!("Stopping on request from the control socket");
+                self.running.store(false, Ordering::SeqCst);
+                self.key_mapper.wake();
+                ok(Vec::new())
+            }
+            _ => error(format!("Unknown command: {}", command)),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let (_, current) = self.key_mapper.current();
+        let config = self
+            .config_path
+            .as_ref()
+            .map(|path| path.display().to_string());
+        ok(vec![
+            ("version", env!("CARGO_PKG_VERSION").into()),
+            ("config", config.into()),
+            ("profile", current.profile().name().into()),
+            ("profiles", current.profile_names().into()),
+            ("paused", self.key_mapper.is_paused().into()),
+        ])
+    }
+
+    fn mappings(&self, profile: Option<&str>) -> Value {
+        let (_, mut current) = self.key_mapper.current();
+        if let Some(name) = profile {
+            if let Err(e) = current.set_profile(name) {
+                return error(e);
+            }
+        }
+
+        let profile = current.profile();
+        let buttons = |keys: Vec<(usize, String)>| {
+            Value::Object(
+                keys.into_iter()
+                    .map(|(index, action)| ((index + 1).to_string(), action.into()))
+                    .collect::<Map<_, _>>(),
+            )
+        };
+        let keys = profile
+            .keys
+            .iter()
+            .map(ToString::to_string)
+            .enumerate()
+            .collect();
+        let mut layers: Vec<_> = profile.layers.iter().collect();
+        layers.sort_by(|a, b| a.0.cmp(b.0));
+        let layers = layers
+            .into_iter()
+            .map(|(name, layer)| {
+                let keys = layer
+                    .keys
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, action)| Some((index, action.as_ref()?.to_string())))
+                    .collect();
+                (name.clone(), buttons(keys))
+            })
+            .collect::<Map<_, _>>();
+
+        ok(vec![
+            ("profile", profile.name().into()),
+            ("keys", buttons(keys)),
+            ("layers", Value::Object(layers)),
+        ])
+    }
+}
+
+fn ok(fields: Vec<(&str, Value)>) -> Value {
+    let mut reply = Map::new();
+    reply.insert("ok".to_string(), Value::Bool(true));
+    reply.extend(fields.into_iter().map(|(key, value)| (key.to_string(), value)));
+    Value::Object(reply)
+}
+
+fn error(message: String) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+/// Looks up a group by name.
+fn group_id(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        let mut group: libc::group = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        let status = unsafe {
+            libc::getgrnam_r(c_name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result)
+        };
+        match status {
+            0 if result.is_null() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Unknown group: {}", name),
+                ))
+            }
+            0 => return Ok(group.gr_gid),
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_map::KeyMapper;
+    use std::time::Instant;
+
+    #[test]
+    fn stop_ends_the_thread_and_removes_the_socket() {
+        let socket = std::env::temp_dir().join(format!("naga-control-{}.sock", std::process::id()));
+        let config = format!("[control]\nsocket = {:?}\n", socket.display().to_string());
+        let key_mapper = KeyMapper::from_toml_str(&config).unwrap();
+        let settings = key_mapper.control().clone();
+        let key_mapper = Arc::new(SharedKeyMapper::new(key_mapper));
+        let running = Arc::new(AtomicBool::new(true));
+        let served = serve(&settings, key_mapper, running.clone(), None).unwrap();
+
+        let reply = request(settings.socket(), r#"{"command": "status"}"#).unwrap();
+        assert!(reply.contains(r#""ok":true"#), "{}", reply);
+
+        // A client that connects and says nothing doesn't hold up shutdown
+        let _idle = UnixStream::connect(&socket).unwrap();
+        let reply = request(settings.socket(), r#"{"command": "stop"}"#).unwrap();
+        assert!(reply.contains(r#""ok":true"#), "{}", reply);
+        assert!(!running.load(Ordering::SeqCst));
+
+        let start = Instant::now();
+        served.join().unwrap();
+        assert!(start.elapsed() < poll::RUNNING_CHECK * 2);
+        assert!(!socket.exists());
+    }
+}
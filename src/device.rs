@@ -0,0 +1,273 @@
+//! Which input device to remap, and which of its key codes are the side
+//! buttons.
+//!
+//! Built-in [`Preset`]s describe the mice known to work. The `[device]`
+//! table of a config picks one, adjusts it, or describes another device.
+
+use regex::Regex;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the preset used when the config has no `[device]` table.
+pub const DEFAULT_PRESET: &str = "naga-2014";
+
+/// Most buttons a device can have, as configs number them 1 to 12.
+pub const MAX_BUTTONS: usize = 12;
+
+/// Side buttons sending the number row, `KEY_1` to `KEY_EQUAL`.
+const NUMBER_ROW: &[u16] = &[2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13];
+
+const RAZER: u16 = 0x1532;
+const LOGITECH: u16 = 0x046d;
+
+/// A mouse the mapper knows how to find.
+pub struct Preset {
+    /// The name used for the preset in configs.
+    pub name: &'static str,
+    pub description: &'static str,
+    vendor: u16,
+    // Every product id the mouse is sold under, such as wired and wireless
+    products: &'static [u16],
+    // Device names gained a suffix such as " Keyboard" in newer kernels, so
+    // these are only anchored at the start
+    name_pattern: &'static str,
+    // USB interface with the side buttons
+    phys: &'static str,
+    // USB interface with the main buttons, wheel and motion
+    pointer_phys: &'static str,
+    buttons: &'static [u16],
+}
+
+/// Every built-in preset.
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "naga-2014",
+        description: "Razer Naga 2014",
+        vendor: RAZER,
+        products: &[0x0040],
+        name_pattern: "^Razer Razer Naga 2014",
+        phys: "/input2",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+    Preset {
+        name: "naga-trinity",
+        description: "Razer Naga Trinity",
+        vendor: RAZER,
+        products: &[0x0067],
+        name_pattern: "^Razer Razer Naga Trinity",
+        phys: "/input2",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+    Preset {
+        name: "naga-pro",
+        description: "Razer Naga Pro, wired or through its dongle",
+        vendor: RAZER,
+        products: &[0x008f, 0x0090],
+        name_pattern: "^Razer Razer Naga Pro",
+        phys: "/input2",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+    Preset {
+        name: "naga-x",
+        description: "Razer Naga X",
+        vendor: RAZER,
+        products: &[0x0096],
+        name_pattern: "^Razer Razer Naga X",
+        phys: "/input2",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+    Preset {
+        name: "naga-epic-chroma",
+        description: "Razer Naga Epic Chroma, wired or on its dock",
+        vendor: RAZER,
+        products: &[0x003e, 0x003f],
+        name_pattern: "^Razer Razer Naga Epic Chroma",
+        phys: "/input1",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+    Preset {
+        name: "naga-hex",
+        description: "Razer Naga Hex (6 buttons)",
+        vendor: RAZER,
+        products: &[0x0036, 0x0041],
+        name_pattern: "^Razer Razer Naga Hex$|^Razer Razer Naga Hex [^V]",
+        phys: "/input1",
+        pointer_phys: "/input0",
+        buttons: &[2, 3, 4, 5, 6, 7],
+    },
+    Preset {
+        name: "naga-hex-v2",
+        description: "Razer Naga Hex V2 (7 buttons)",
+        vendor: RAZER,
+        products: &[0x0050],
+        name_pattern: "^Razer Razer Naga Hex V2",
+        phys: "/input2",
+        pointer_phys: "/input0",
+        buttons: &[2, 3, 4, 5, 6, 7, 8],
+    },
+    Preset {
+        name: "g600",
+        description: "Logitech G600, G9 to G20 on their default keys",
+        vendor: LOGITECH,
+        products: &[0xc24a],
+        name_pattern: "G600",
+        phys: "/input1",
+        pointer_phys: "/input0",
+        buttons: NUMBER_ROW,
+    },
+];
+
+/// Looks up a built-in preset by its config name.
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+impl Preset {
+    /// The device settings the preset stands for.
+    pub fn settings(&self) -> DeviceSettings {
+        DeviceSettings {
+            preset: Some(self.name),
+            matcher: DeviceMatcher {
+                vendor: Some(self.vendor),
+                products: self.products.to_vec(),
+                name: Some(Regex::new(self.name_pattern).expect("preset patterns are valid")),
+                phys: Some(self.phys.to_string()),
+                path: None,
+            },
+            pointer_phys: Some(self.pointer_phys.to_string()),
+            pointer_path: None,
+            buttons: self.buttons.to_vec(),
+            passthrough: false,
+        }
+    }
+}
+
+/// What is known about an event device, for matching it.
+pub struct DeviceIdentity<'a> {
+    pub path: &'a Path,
+    pub name: &'a str,
+    pub phys: &'a str,
+    pub vendor: u16,
+    pub product: u16,
+}
+
+/// How to recognise one of a mouse's event devices. Every
+/// criterion that is set must hold.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceMatcher {
+    pub(crate) vendor: Option<u16>,
+    // Any of these, when there are some
+    pub(crate) products: Vec<u16>,
+    pub(crate) name: Option<Regex>,
+    // End of the device's `phys`, which tells a mouse's USB interfaces apart
+    pub(crate) phys: Option<String>,
+    // A path that is, or links to, the event device, e.g. under
+    // `/dev/input/by-id`
+    pub(crate) path: Option<PathBuf>,
+}
+// Patterns are the same if they are written the same
+impl PartialEq for DeviceMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.vendor == other.vendor
+            && self.products == other.products
+            && self.name.as_ref().map(Regex::as_str) == other.name.as_ref().map(Regex::as_str)
+            && self.phys == other.phys
+            && self.path == other.path
+    }
+}
+impl DeviceMatcher {
+    /// Whether nothing is set, so that every device would match.
+    pub fn is_empty(&self) -> bool {
+        *self == DeviceMatcher::default()
+    }
+
+    pub fn matches(&self, device: &DeviceIdentity) -> bool {
+        self.vendor.is_none_or(|vendor| vendor == device.vendor)
+            && (self.products.is_empty() || self.products.contains(&device.product))
+            && self.name.as_ref().is_none_or(|name| name.is_match(device.name))
+            && self.phys.as_ref().is_none_or(|phys| device.phys.ends_with(phys.as_str()))
+            && self.path.as_ref().is_none_or(|path| same_file(path, device.path))
+    }
+}
+
+/// Whether two paths lead to the same file once symlinks are followed.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The device to grab and the key codes of its side buttons.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceSettings {
+    // The preset the settings started from, if any
+    pub(crate) preset: Option<&'static str>,
+    pub(crate) matcher: DeviceMatcher,
+    // Where the pointer interface differs from the side buttons' one
+    pub(crate) pointer_phys: Option<String>,
+    pub(crate) pointer_path: Option<PathBuf>,
+    // Key code sent by each button, button 1 first
+    pub(crate) buttons: Vec<u16>,
+    // Whether events the mapping doesn't use are passed on rather than
+    // dropped; off unless the config turns it on
+    pub(crate) passthrough: bool,
+}
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        preset(DEFAULT_PRESET).expect("the default preset exists").settings()
+    }
+}
+impl DeviceSettings {
+    /// Settings for a device that no preset knows, matching nothing yet.
+    pub fn custom() -> DeviceSettings {
+        DeviceSettings {
+            preset: None,
+            matcher: DeviceMatcher::default(),
+            pointer_phys: None,
+            pointer_path: None,
+            buttons: NUMBER_ROW.to_vec(),
+            passthrough: false,
+        }
+    }
+
+    pub fn preset(&self) -> Option<&str> {
+        self.preset
+    }
+
+    pub fn matcher(&self) -> &DeviceMatcher {
+        &self.matcher
+    }
+
+    /// How to recognise the same mouse's pointer interface, grabbed when
+    /// `[buttons]` or `[wheel]` remap it: the same ids and name, but its own
+    /// phys or path.
+    pub fn pointer_matcher(&self) -> DeviceMatcher {
+        DeviceMatcher {
+            phys: self.pointer_phys.clone(),
+            path: self.pointer_path.clone(),
+            ..self.matcher.clone()
+        }
+    }
+
+    /// Key codes of the side buttons, button 1 first.
+    pub fn buttons(&self) -> &[u16] {
+        &self.buttons
+    }
+
+    /// Whether keys the side buttons don't send, scan codes, LED state and
+    /// other events the mapping has no use for reach the virtual device.
+    pub fn passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// The 0-based index of the button that sends key `code`.
+    pub fn button_index(&self, code: u16) -> Option<usize> {
+        self.buttons.iter().position(|&button| button == code)
+    }
+}
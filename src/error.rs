@@ -0,0 +1,95 @@
+//! The error type returned throughout the library.
+
+use crate::key_map::ConfigErrors;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+/// Something that stopped the Naga from being found, grabbed or remapped.
+///
+/// Match on the variant to react to a particular failure, e.g. to tell the
+/// user to join the `input` group on [`Error::PermissionDenied`].
+#[derive(Debug)]
+pub enum Error {
+    /// No device matching the `[device]` settings, by default the Naga
+    /// 2014's side button interface, was found under `/dev/input`.
+    DeviceNotFound,
+    /// The mouse was unplugged while it was being read.
+    DeviceRemoved,
+    /// A device the mapper needs could not be opened for lack of permission.
+    PermissionDenied { path: PathBuf },
+    /// The Naga was found but could not be grabbed or released, usually
+    /// because another program holds it.
+    GrabFailed(io::Error),
+    /// `/dev/uinput` is missing or the virtual keyboard could not be created.
+    UinputUnavailable(io::Error),
+    /// The config file could not be read or has mistakes.
+    Config(ConfigErrors),
+    /// Any other failed system call; the errno is in `raw_os_error()`.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Whether this is a read that found no event waiting on a non-blocking
+    /// device.
+    pub fn is_would_block(&self) -> bool {
+        matches!(self, Error::Io(e) if e.kind() == io::ErrorKind::WouldBlock)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DeviceNotFound => f.write_str("No matching mouse found (see `list-devices`)"),
+            Error::DeviceRemoved => f.write_str("The mouse was unplugged"),
+            Error::PermissionDenied { path } => write!(
+                f,
+                "Permission denied opening {} (run as root, or add yourself to the `input` group)",
+                path.display()
+            ),
+            Error::GrabFailed(e) => write!(f, "Could not grab device: {}", e),
+            Error::UinputUnavailable(e) => write!(f, "Could not create virtual keyboard: {}", e),
+            Error::Config(errors) => write!(f, "{}", errors),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::GrabFailed(e) | Error::UinputUnavailable(e) | Error::Io(e) => Some(e),
+            Error::Config(errors) => Some(errors),
+            Error::DeviceNotFound | Error::DeviceRemoved | Error::PermissionDenied { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ConfigErrors> for Error {
+    fn from(errors: ConfigErrors) -> Self {
+        Error::Config(errors)
+    }
+}
+
+impl From<uinput::Error> for Error {
+    fn from(e: uinput::Error) -> Self {
+        match e {
+            // uinput wraps its own version of nix, but errno still holds
+            // the failure of the call it just made
+            uinput::Error::Nix(_) => Error::Io(io::Error::last_os_error()),
+            e => Error::Io(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// Converts an errno reported by evdev, whose `Errno` values are the raw
+/// numbers.
+pub(crate) fn from_errno(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
@@ -1,10 +1,24 @@
-use crate::key_map::KeyMapper;
-use crate::naga::Naga;
-use evdev_rs::enums::EventCode::{EV_KEY, EV_SYN};
-use evdev_rs::InputEvent;
-use uinput::device::Device;
+use crate::exec;
+use crate::input_device::{self, Output};
+use crate::key_map::{
+    self, Action, Chord, KeyMapper, Macro, MacroStep, ProfileSwitch, TapDance, TapHold, Turbo,
+};
+use crate::macro_player::MacroPlayer;
+use crate::naga::{Event, Interface, Naga};
+use crate::poll;
+use crate::reload::{Generation, SharedKeyMapper};
 use uinput::Error;
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use uinput_sys::{EV_KEY, EV_MSC, EV_REL, EV_SYN, REL_HWHEEL, REL_WHEEL};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, MutexGuard, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+
+/// Longest wait when there is no waker to end it early.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+// Not in uinput-sys, which predates high resolution scrolling
+const REL_WHEEL_HI_RES: u16 = 0x0b;
+const REL_HWHEEL_HI_RES: u16 = 0x0c;
 
 macro_rules! debug_println {
     ($($arg:tt)*) => {
@@ -18,11 +32,31 @@ macro_rules! debug_println {
 }
 
 pub fn map_events(
-    key_mapper: KeyMapper,
-    naga: Naga,
-    device: &mut Device,
+    key_mapper: &SharedKeyMapper,
+    mut naga: Naga,
+    device: Arc<Mutex<Output>>,
     running: Arc<AtomicBool>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), crate::Error> {
+    let (generation, current) = key_mapper.current();
+    let mut mapper = Mapper::new(current, generation, device);
+    let result = read_events(&mut mapper, key_mapper, &mut naga, &running);
+
+    // Stop any macros still playing so they don't outlive the device, and
+    // let go of everything held so nothing stays stuck on the virtual keyboard
+    mapper.cancel_macros();
+    if let Err(e) = mapper.release_all() {
+        eprintln!("Error releasing held keys: {}", e);
+    }
+
+    result
+}
+
+fn read_events(
+    mapper: &mut Mapper,
+    key_mapper: &SharedKeyMapper,
+    naga: &mut Naga,
+    running: &AtomicBool,
+) -> Result<(), crate::Error> {
     loop {
         // Check if we should stop
         if !running.load(Ordering::SeqCst) {
@@ -31,74 +65,687 @@ pub fn map_events(
             break;
         }
 
+        // Let go of the mouse while remapping is paused
+        let paused = key_mapper.is_paused();
+        if paused != mapper.paused {
+            if paused {
+                mapper.release_everything()?;
+            }
+            naga.set_grabbed(!paused)?;
+            mapper.paused = paused;
+            debug_println!("Remapping {}", if paused { "paused" } else { "resumed" });
+        }
+
+        // Pick up a reloaded config between events
+        if key_mapper.generation() != mapper.generation.changes {
+            let (generation, current) = key_mapper.current();
+            // A different `[device]` may be another mouse, new `[buttons]`
+            // or `[wheel]` may need the pointer grabbed, and a different
+            // `[virtual_device]` needs a new virtual keyboard; let go so the
+            // run loop attaches again
+            if current.device() != mapper.key_mapper.device()
+                || current.grabs_pointer() != mapper.key_mapper.grabs_pointer()
+                || current.virtual_device() != mapper.key_mapper.virtual_device()
+            {
+                debug_println!("Device settings changed, detaching");
+                break;
+            }
+            mapper.swap(current, generation)?;
+        }
+
         // Try to read event (non-blocking now)
         match naga.next_event() {
-            Ok((_read_status, input_event)) => {
-                process_event(key_mapper, input_event, device)
-                    .map_err(|e| format!("Process event error: {}", e))?;
-            }
-            Err(e) => {
-                // Check if it's a "would block" error (no data available)
-                if e.contains("Resource temporarily unavailable") || e.contains("EAGAIN") {
-                    // No data available, sleep briefly and check running flag again
-                    std::thread::sleep(std::time::Duration::from_millis(50));
-                    continue;
-                } else {
-                    // Got conditions for real error
-                    return Err(e.into());
+            // Events from an ungrabbed mouse also reach the desktop
+            Ok(_) if mapper.paused => {}
+            Ok((interface, event)) => {
+                match interface {
+                    Interface::SideButtons => mapper.process_event(event)?,
+                    Interface::Pointer => mapper.process_pointer_event(event)?,
+                }
+                // Profile buttons switch the shared mapping, which the
+                // next iteration then swaps in
+                if let Some(switch) = mapper.profile_switch.take() {
+                    if let Err(e) = key_mapper.switch_profile(&switch) {
+                        eprintln!("Could not switch profile: {}", e);
+                    }
                 }
             }
+            // No data available, fire any due timers then wait for input,
+            // the next timer or a wake-up, and check running flag again
+            Err(e) if e.is_would_block() => {
+                let now = monotonic_now();
+                mapper.tick(now)?;
+                let waker = key_mapper.waker();
+                let longest = if waker.is_some() { poll::RUNNING_CHECK } else { IDLE_POLL };
+                let timeout = mapper
+                    .next_deadline()
+                    .map_or(longest, |deadline| deadline.saturating_sub(now).min(longest));
+                poll::wait(&naga.fds(), waker, Some(timeout))?;
+            }
+            Err(e) => return Err(e),
         }
     }
-    
+
     Ok(())
 }
 
-fn process_event(
+/// Per-attachment mapping state.
+struct Mapper {
     key_mapper: KeyMapper,
-    event: InputEvent,
-    input_device: &mut Device,
-) -> Result<(), Error> {
-    match event.event_code {
-        EV_KEY(key) => {
-            // Map hardware event codes to key mapper indices
-            // Naga 2014 side buttons send codes 2-13 (corresponding to 1-0,-,= keys)
-            // Convert EV_KEY enum to its numeric code
-            let key_code = key as u32;
-            let key_index = match key_code {
-                2..=13 => Some((key_code - 2) as usize),  // Keys 1-12 map to indices 0-11
-                _ => None,
-            };
-            
-            if let Some(index) = key_index {
-                if let Some(mapped_key) = key_mapper.keys.get(index).copied() {
-                    #[cfg(debug_assertions)]
-                    let action = match event.value {
-                        1 => "PRESSED",
-                        0 => "RELEASED",
-                        _ => "UNKNOWN",
+    // Generation of the shared mapping `key_mapper` was copied from
+    generation: Generation,
+    device: Arc<Mutex<Output>>,
+    // Macros currently playing, keyed by button index
+    macros: HashMap<usize, MacroPlayer>,
+    // Names of the held layer keys' layers, most recent last
+    layers: Vec<String>,
+    // The action each held button resolved to when it was pressed, so the
+    // release goes to the same keys even if the active layer has changed
+    pressed: HashMap<usize, Action>,
+    // Tap-hold buttons currently down, keyed by button index
+    tap_holds: HashMap<usize, TapHoldState>,
+    // Tap-dance buttons still waiting for more taps, keyed by button index
+    tap_dances: HashMap<usize, TapDanceState>,
+    // Chords latched down by toggle buttons, keyed by button index
+    latched: HashMap<usize, Chord>,
+    // Turbo buttons currently held, keyed by button index
+    turbos: HashMap<usize, TurboState>,
+    // Buttons held across a mapping swap, whose release belongs to the old
+    // mapping and has already been handled
+    stale: HashSet<usize>,
+    // Codes of keys and buttons that were passed on and are still down, so
+    // they can be let go of if the mouse goes away
+    forwarded: HashSet<u16>,
+    // `EV_MSC` events of the report being read, held back until its key
+    // shows whether they should be passed on
+    scan_codes: Vec<Event>,
+    // A profile switch requested by a button, applied by `read_events`
+    profile_switch: Option<ProfileSwitch>,
+    // Whether the mouse has been let go because remapping is paused
+    paused: bool,
+}
+
+/// A tap-hold button that is down and may not have been decided yet.
+struct TapHoldState {
+    tap_hold: TapHold,
+    // Event timestamp of the press
+    pressed_at: Duration,
+    // Whether the hold chord has been pressed
+    holding: bool,
+}
+
+impl TapHoldState {
+    fn deadline(&self) -> Duration {
+        self.pressed_at + Duration::from_millis(self.tap_hold.hold_ms)
+    }
+}
+
+/// A tap-dance button counting taps until its window runs out.
+struct TapDanceState {
+    tap_dance: TapDance,
+    taps: usize,
+    // Event timestamp of the most recent press
+    last_press: Duration,
+}
+
+/// A held turbo button alternating between pressed and released.
+struct TurboState {
+    turbo: Turbo,
+    // Whether the chord is currently down
+    down: bool,
+    // When to flip `down` next
+    next: Duration,
+}
+
+impl TapDanceState {
+    fn deadline(&self) -> Duration {
+        self.last_press + Duration::from_millis(self.tap_dance.window_ms)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.taps >= self.tap_dance.taps.len()
+    }
+}
+
+impl Mapper {
+    fn new(key_mapper: KeyMapper, generation: Generation, device: Arc<Mutex<Output>>) -> Mapper {
+        let mut output = lock(&device);
+        output.set_pointer(key_mapper.uses_pointer());
+        output.set_gamepad(key_mapper.uses_gamepad());
+        drop(output);
+        Mapper {
+            key_mapper,
+            generation,
+            device,
+            macros: HashMap::new(),
+            layers: Vec::new(),
+            pressed: HashMap::new(),
+            tap_holds: HashMap::new(),
+            tap_dances: HashMap::new(),
+            latched: HashMap::new(),
+            turbos: HashMap::new(),
+            stale: HashSet::new(),
+            forwarded: HashSet::new(),
+            scan_codes: Vec::new(),
+            profile_switch: None,
+            paused: false,
+        }
+    }
+
+    /// Switches to a new mapping. A reloaded config first lets go of
+    /// everything the old one was holding; a profile switch keeps held
+    /// keys down until their buttons are released, like a layer change.
+    fn swap(&mut self, key_mapper: KeyMapper, generation: Generation) -> Result<(), Error> {
+        if generation.reloads != self.generation.reloads {
+            self.release_everything()?;
+            debug_println!("Switched to new key mapping");
+        } else {
+            debug_println!("Switched to profile {}", key_mapper.profile().name());
+        }
+        let mut device = self.device();
+        device.set_pointer(key_mapper.uses_pointer());
+        device.set_gamepad(key_mapper.uses_gamepad());
+        drop(device);
+        self.key_mapper = key_mapper;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn device(&self) -> MutexGuard<'_, Output> {
+        lock(&self.device)
+    }
+
+    fn process_event(&mut self, event: Event) -> Result<(), Error> {
+        // Settle any timers that expired before this event happened
+        let time = event.time;
+        self.tick(time)?;
+
+        match event.kind as libc::c_int {
+            EV_KEY => {
+                // Map hardware event codes to key mapper indices; which
+                // codes the side buttons send depends on the mouse
+                let key_index = self.key_mapper.device().button_index(event.code);
+
+                match key_index {
+                    Some(index) => self.process_button(index, event.value, time)?,
+                    None if self.key_mapper.device().passthrough() => {
+                        self.pass_key(event.code, event.value)?
+                    }
+                    None => {}
+                }
+                // Scan codes only go with keys that were passed on
+                self.scan_codes.clear();
+            }
+            EV_MSC => self.scan_codes.push(event),
+            EV_SYN => {
+                let mut device = lock(&self.device);
+                // Scan codes without a key are passed on like anything else
+                if self.key_mapper.device().passthrough() {
+                    for scan_code in self.scan_codes.drain(..) {
+                        input_device::forward(&mut device, &scan_code)?;
+                    }
+                }
+                self.scan_codes.clear();
+                device.synchronize()?
+            }
+            _ if self.key_mapper.device().passthrough() => {
+                input_device::forward(&mut self.device(), &event)?
+            }
+            _ => (),
+        };
+        Ok(())
+    }
+
+    /// Passes a key on as the mouse sent it, after the scan codes that came
+    /// with it, and keeps track of it while it is down.
+    fn pass_key(&mut self, code: u16, value: i32) -> Result<(), Error> {
+        match value {
+            1 => self.forwarded.insert(code),
+            0 => self.forwarded.remove(&code),
+            _ => false,
+        };
+        let mut device = lock(&self.device);
+        // The scan codes go to the same device as the key
+        let pointer = input_device::is_pointer_event(EV_KEY, code as libc::c_int);
+        for scan_code in self.scan_codes.drain(..) {
+            if pointer {
+                input_device::forward_pointer(&mut device, &scan_code)?;
+            } else {
+                input_device::forward(&mut device, &scan_code)?;
+            }
+        }
+        device.write(EV_KEY, code as libc::c_int, value)
+    }
+
+    /// Whether a mouse button or wheel direction is bound to something
+    /// other than `Passthrough`.
+    fn remaps(&self, index: usize) -> bool {
+        self.key_mapper
+            .pointer
+            .get(&index)
+            .is_some_and(|action| !matches!(action, Action::Passthrough))
+    }
+
+    /// The code the mouse sends for a button index, if it is a button.
+    fn button_code(&self, index: usize) -> Option<u16> {
+        match index.checked_sub(key_map::FIRST_MOUSE_BUTTON) {
+            None => self.key_mapper.device().buttons().get(index).copied(),
+            Some(offset) => key_map::MOUSE_BUTTONS.get(offset).map(|(_, code)| *code),
+        }
+    }
+
+    /// Handles an event from the pointer interface: bound buttons and wheel
+    /// directions are remapped, everything else is passed on as it is.
+    fn process_pointer_event(&mut self, event: Event) -> Result<(), Error> {
+        let time = event.time;
+        self.tick(time)?;
+
+        match (event.kind as libc::c_int, key_map::mouse_button_index(event.code)) {
+            (EV_KEY, Some(index)) => {
+                // A button pressed while unbound is released the same way,
+                // even if a reload has bound it since
+                let forwarded = match event.value {
+                    1 => !self.remaps(index) && self.forwarded.insert(event.code),
+                    0 => self.forwarded.remove(&event.code),
+                    _ => self.forwarded.contains(&event.code),
+                };
+                if !forwarded {
+                    self.scan_codes.clear();
+                    return self.process_button(index, event.value, time);
+                }
+            }
+            (EV_REL, _) => {
+                if let Some(index) = wheel_direction(&event) {
+                    if !self.remaps(index) {
+                        return input_device::forward_pointer(&mut self.device(), &event);
+                    }
+                    // Each step of the wheel is a tap; the high resolution
+                    // events that go with the steps are dropped
+                    if matches!(event.code as libc::c_int, REL_WHEEL | REL_HWHEEL) {
+                        for _ in 0..event.value.unsigned_abs() {
+                            self.process_button(index, 1, time)?;
+                            self.process_button(index, 0, time)?;
+                        }
+                    }
+                    return Ok(());
+                }
+            }
+            (EV_MSC, _) => {
+                self.scan_codes.push(event);
+                return Ok(());
+            }
+            _ => {}
+        }
+        let mut device = lock(&self.device);
+        if matches!(event.kind as libc::c_int, EV_KEY | EV_SYN) {
+            for scan_code in self.scan_codes.drain(..) {
+                input_device::forward_pointer(&mut device, &scan_code)?;
+            }
+        }
+        input_device::forward_pointer(&mut device, &event)
+    }
+
+    fn process_button(&mut self, index: usize, value: i32, time: Duration) -> Result<(), Error> {
+        // Pressing another button settles anything still undecided: a
+        // tap-hold is being used as a modifier, and a tap-dance is over
+        if value == 1 {
+            self.resolve_pending(index)?;
+        }
+
+        // A press made under the previous mapping was already released
+        // when the mapping changed
+        if self.stale.remove(&index) && value == 0 {
+            return Ok(());
+        }
+
+        let action = match value {
+            1 => {
+                let action = self.resolve(index);
+                if let Some(action) = &action {
+                    self.pressed.insert(index, action.clone());
+                }
+                action
+            }
+            0 => self.pressed.remove(&index).or_else(|| self.resolve(index)),
+            _ => return Ok(()),
+        };
+
+        let Some(action) = action else {
+            debug_println!("No mapped key for {} (index {})", key_map::button_name(index), index);
+            return Ok(());
+        };
+
+        #[cfg(debug_assertions)]
+        let state = if value == 1 { "PRESSED" } else { "RELEASED" };
+
+        debug_println!(
+            "{} (index {}) {} -> Key: {}",
+            key_map::button_name(index),
+            index,
+            state,
+            action.debug_name()
+        );
+
+        match action {
+            Action::Keys(chord) => {
+                // Modifiers go down in the order written and come back
+                // up in reverse, so "LeftControl+T" releases T first
+                let mut device = self.device();
+                match value {
+                    1 => input_device::press(&mut device, &chord)?,
+                    _ => input_device::release(&mut device, &chord)?,
+                }
+            }
+            Action::Macro(name) => {
+                if value == 1 {
+                    if let Some(macro_) = self.key_mapper.macros.get(&name).cloned() {
+                        self.toggle_macro(index, &name, macro_);
+                    }
+                }
+            }
+            Action::Text(text) => {
+                if value == 1 {
+                    let macro_ = Macro {
+                        steps: vec![MacroStep::Text(text)],
+                    };
+                    self.toggle_macro(index, "text", Arc::new(macro_));
+                }
+            }
+            Action::Layer(name) => {
+                if value == 1 {
+                    self.layers.push(name);
+                } else if let Some(pos) = self.layers.iter().rposition(|l| *l == name) {
+                    self.layers.remove(pos);
+                }
+            }
+            Action::TapHold(tap_hold) => {
+                if value == 1 {
+                    let state = TapHoldState {
+                        tap_hold,
+                        pressed_at: time,
+                        holding: false,
+                    };
+                    self.tap_holds.insert(index, state);
+                } else if let Some(state) = self.tap_holds.remove(&index) {
+                    let mut device = self.device();
+                    if state.holding {
+                        input_device::release(&mut device, &state.tap_hold.hold)?;
+                    } else {
+                        debug_println!("Button {} tapped", index + 1);
+                        input_device::tap(&mut device, &state.tap_hold.tap)?;
+                    }
+                }
+            }
+            Action::TapDance(tap_dance) => {
+                if value == 1 {
+                    let state = self.tap_dances.entry(index).or_insert(TapDanceState {
+                        tap_dance,
+                        taps: 0,
+                        last_press: time,
+                    });
+                    state.taps += 1;
+                    state.last_press = time;
+
+                    if state.is_complete() {
+                        self.finish_tap_dance(index)?;
+                    }
+                }
+            }
+            Action::Toggle(chord) => {
+                if value == 1 {
+                    let mut device = lock(&self.device);
+                    if let Some(latched) = self.latched.remove(&index) {
+                        debug_println!("Button {} unlatched", index + 1);
+                        input_device::release(&mut device, &latched)?;
+                    } else {
+                        debug_println!("Button {} latched", index + 1);
+                        input_device::press(&mut device, &chord)?;
+                        self.latched.insert(index, chord);
+                    }
+                }
+            }
+            Action::Exec(command) => {
+                if value == 1 {
+                    if let Err(e) = exec::spawn(&command) {
+                        eprintln!("Could not run {}: {}", command.program, e);
+                    }
+                }
+            }
+            Action::Profile(switch) => {
+                if value == 1 {
+                    self.profile_switch = Some(switch);
+                }
+            }
+            Action::Scroll(scroll) => {
+                if value == 1 {
+                    input_device::scroll(&mut self.device(), &scroll)?;
+                }
+            }
+            Action::Move(x, y) => {
+                if value == 1 {
+                    input_device::move_pointer(&mut self.device(), x, y)?;
+                }
+            }
+            Action::DoubleClick(button) => {
+                if value == 1 {
+                    input_device::double_click(&mut self.device(), button)?;
+                }
+            }
+            Action::Passthrough => {
+                if let Some(code) = self.button_code(index) {
+                    self.pass_key(code, value)?;
+                }
+            }
+            Action::Disabled => {}
+            Action::Turbo(turbo) => {
+                if value == 1 {
+                    input_device::press(&mut self.device(), &turbo.chord)?;
+                    let state = TurboState {
+                        next: time + turbo.half_period(),
+                        turbo,
+                        down: true,
                     };
-                    
-                    debug_println!(
-                        "Button {} (index {}) {} -> Key: {}",
-                        index + 1,
-                        index,
-                        action,
-                        mapped_key.debug_name()
-                    );
-                    
-                    match event.value {
-                        1 => input_device.press(&mapped_key)?,
-                        0 => input_device.release(&mapped_key)?,
-                        _ => (),
+                    self.turbos.insert(index, state);
+                } else if let Some(state) = self.turbos.remove(&index) {
+                    if state.down {
+                        input_device::release(&mut self.device(), &state.turbo.chord)?;
                     }
-                } else {
-                    debug_println!("No mapped key for button {} (index {})", index + 1, index);
                 }
             }
         }
-        EV_SYN(_) => input_device.synchronize()?,
-        _ => (),
+        Ok(())
+    }
+
+    /// Stops macros and releases all keys, ignoring the releases of buttons
+    /// that are held right now.
+    fn release_everything(&mut self) -> Result<(), Error> {
+        self.cancel_macros();
+        self.stale.extend(self.pressed.keys().copied());
+        self.release_all()
+    }
+
+    /// Releases every key the mapper is holding down: latched toggles,
+    /// held tap-holds and buttons that are still physically pressed.
+    fn release_all(&mut self) -> Result<(), Error> {
+        let mut device = lock(&self.device);
+
+        for (_, chord) in self.latched.drain() {
+            input_device::release(&mut device, &chord)?;
+        }
+        for (_, state) in self.tap_holds.drain() {
+            if state.holding {
+                input_device::release(&mut device, &state.tap_hold.hold)?;
+            }
+        }
+        for (_, state) in self.turbos.drain() {
+            if state.down {
+                input_device::release(&mut device, &state.turbo.chord)?;
+            }
+        }
+        for (_, action) in self.pressed.drain() {
+            if let Action::Keys(chord) = action {
+                input_device::release(&mut device, &chord)?;
+            }
+        }
+        for code in self.forwarded.drain() {
+            device.write(EV_KEY, code as libc::c_int, 0)?;
+            device.synchronize()?;
+        }
+        self.scan_codes.clear();
+        self.tap_dances.clear();
+        self.layers.clear();
+        Ok(())
+    }
+
+    /// Sends the chord for however many taps a tap-dance button received.
+    fn finish_tap_dance(&mut self, index: usize) -> Result<(), Error> {
+        if let Some(state) = self.tap_dances.remove(&index) {
+            let taps = state.taps.min(state.tap_dance.taps.len());
+            debug_println!("Button {} tapped {} time(s)", index + 1, taps);
+            input_device::tap(&mut self.device(), &state.tap_dance.taps[taps - 1])?;
+        }
+        Ok(())
+    }
+
+    /// Fires every timer that is due at `now`.
+    fn tick(&mut self, now: Duration) -> Result<(), Error> {
+        let device = self.device.clone();
+        for (_index, state) in self.tap_holds.iter_mut() {
+            if !state.holding && now >= state.deadline() {
+                debug_println!("Button {} held", _index + 1);
+                state.holding = true;
+                input_device::press(&mut lock(&device), &state.tap_hold.hold)?;
+            }
+        }
+
+        let finished: Vec<usize> = self
+            .tap_dances
+            .iter()
+            .filter(|(_, state)| now >= state.deadline())
+            .map(|(index, _)| *index)
+            .collect();
+        for index in finished {
+            self.finish_tap_dance(index)?;
+        }
+
+        for state in self.turbos.values_mut() {
+            if now < state.next {
+                continue;
+            }
+            let mut device = lock(&device);
+            if state.down {
+                input_device::release(&mut device, &state.turbo.chord)?;
+            } else {
+                input_device::press(&mut device, &state.turbo.chord)?;
+            }
+            state.down = !state.down;
+            // Skip ahead rather than catching up in a burst if we fell behind
+            state.next += state.turbo.half_period();
+            if state.next <= now {
+                state.next = now + state.turbo.half_period();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the time at which `tick` next has work to do.
+    fn next_deadline(&self) -> Option<Duration> {
+        let holds = self
+            .tap_holds
+            .values()
+            .filter(|state| !state.holding)
+            .map(TapHoldState::deadline);
+        let dances = self.tap_dances.values().map(TapDanceState::deadline);
+        let turbos = self.turbos.values().map(|state| state.next);
+        holds.chain(dances).chain(turbos).min()
+    }
+
+    /// Turns every undecided tap-hold into a hold and ends every tap-dance
+    /// other than the one on the button at `index`.
+    fn resolve_pending(&mut self, index: usize) -> Result<(), Error> {
+        let device = self.device.clone();
+        for state in self.tap_holds.values_mut().filter(|state| !state.holding) {
+            state.holding = true;
+            input_device::press(&mut lock(&device), &state.tap_hold.hold)?;
+        }
+
+        let finished: Vec<usize> = self
+            .tap_dances
+            .keys()
+            .copied()
+            .filter(|other| *other != index)
+            .collect();
+        for other in finished {
+            self.finish_tap_dance(other)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the binding for a button, searching the active layers from
+    /// the most recently activated down to the active profile's `[keys]`.
+    fn resolve(&self, index: usize) -> Option<Action> {
+        if index >= key_map::FIRST_MOUSE_BUTTON {
+            return self.key_mapper.pointer.get(&index).cloned();
+        }
+        let profile = self.key_mapper.profile();
+        self.layers
+            .iter()
+            .rev()
+            .filter_map(|name| profile.layers.get(name))
+            .find_map(|layer| layer.keys[index].clone())
+            .or_else(|| profile.keys.get(index).cloned())
+    }
+
+    /// Starts the macro bound to a button, or cancels it if it is still playing.
+    fn toggle_macro(&mut self, index: usize, name: &str, macro_: Arc<Macro>) {
+        if let Some(player) = self.macros.remove(&index) {
+            if !player.is_finished() {
+                debug_println!("Cancelling macro {}", name);
+                player.cancel();
+                return;
+            }
+        }
+
+        let text = self.key_mapper.text.clone();
+        match MacroPlayer::start(name, macro_, text, self.device.clone()) {
+            Ok(player) => {
+                self.macros.insert(index, player);
+            }
+            Err(e) => eprintln!("Could not start macro {}: {}", name, e),
+        }
+    }
+
+    fn cancel_macros(&mut self) {
+        for (_, player) in self.macros.drain() {
+            player.cancel();
+        }
+    }
+}
+
+fn lock(device: &Mutex<Output>) -> MutexGuard<'_, Output> {
+    device.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The `[wheel]` direction a scroll event moves in, as a button index, or
+/// `None` for motion.
+fn wheel_direction(event: &Event) -> Option<usize> {
+    let offset = match (event.code, event.value.signum()) {
+        (_, 0) => return None,
+        (code, 1) if code == REL_WHEEL as u16 || code == REL_WHEEL_HI_RES => 0,
+        (code, -1) if code == REL_WHEEL as u16 || code == REL_WHEEL_HI_RES => 1,
+        (code, -1) if code == REL_HWHEEL as u16 || code == REL_HWHEEL_HI_RES => 2,
+        (code, 1) if code == REL_HWHEEL as u16 || code == REL_HWHEEL_HI_RES => 3,
+        _ => return None,
     };
-    Ok(())
+    Some(key_map::FIRST_WHEEL_DIRECTION + offset)
+}
+
+/// Current time on the same clock as event timestamps, set up by
+/// `Naga::new`.
+fn monotonic_now() -> Duration {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
 }
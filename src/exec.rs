@@ -0,0 +1,150 @@
+//! Launching commands bound to side buttons.
+//!
+//! The daemon usually runs as root, so commands can be configured to run
+//! as another user. Every child is waited on from its own thread so the
+//! event loop never has to reap them.
+
+use crate::key_map::Exec;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The identity a command is started with.
+#[derive(Clone)]
+pub struct RunAs {
+    pub(crate) uid: libc::uid_t,
+    pub(crate) gid: libc::gid_t,
+    // Name and home directory, when the user is in the password database
+    pub(crate) name: Option<String>,
+    pub(crate) home: Option<String>,
+}
+
+impl RunAs {
+    /// Looks up a user by login name.
+    pub fn from_name(name: &str) -> io::Result<Option<RunAs>> {
+        let c_name = CString::new(name)?;
+        lookup(|pwd, buf, result| unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+        })
+    }
+
+    /// Looks up a user by id, falling back to `gid` (or the uid itself as the
+    /// group) for ids that have no password entry.
+    pub fn from_uid(uid: libc::uid_t, gid: Option<libc::gid_t>) -> io::Result<RunAs> {
+        let found = lookup(|pwd, buf, result| unsafe {
+            libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+        })?;
+        Ok(match found {
+            Some(mut user) => {
+                if let Some(gid) = gid {
+                    user.gid = gid;
+                }
+                user
+            }
+            None => RunAs {
+                uid,
+                gid: gid.unwrap_or(uid),
+                name: None,
+                home: None,
+            },
+        })
+    }
+}
+
+fn lookup<F>(getpw: F) -> io::Result<Option<RunAs>>
+where
+    F: Fn(&mut libc::passwd, &mut [libc::c_char], &mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut buf = vec![0 as libc::c_char; 4096];
+    loop {
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result = std::ptr::null_mut();
+        match getpw(&mut pwd, &mut buf, &mut result) {
+            0 if result.is_null() => return Ok(None),
+            0 => {
+                let field = |ptr: *const libc::c_char| {
+                    unsafe { CStr::from_ptr(ptr) }.to_string_lossy().into_owned()
+                };
+                return Ok(Some(RunAs {
+                    uid: pwd.pw_uid,
+                    gid: pwd.pw_gid,
+                    name: Some(field(pwd.pw_name)),
+                    home: Some(field(pwd.pw_dir)),
+                }));
+            }
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+/// The groups `name` belongs to, `gid` among them.
+fn group_list(name: &str, gid: libc::gid_t) -> io::Result<Vec<libc::gid_t>> {
+    let c_name = CString::new(name)?;
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let found = unsafe { libc::getgrouplist(c_name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if found >= 0 {
+            groups.truncate(count as usize);
+            return Ok(groups);
+        }
+        // Too small; `count` now says how many there are
+        let needed = (count as usize).max(groups.len() * 2);
+        groups.resize(needed, 0);
+    }
+}
+
+/// Starts the command without waiting for it to finish.
+pub(crate) fn spawn(exec: &Exec) -> io::Result<()> {
+    let mut command = Command::new(&exec.program);
+    command
+        .args(&exec.args)
+        .stdin(Stdio::null())
+        // Keep the child out of our process group so terminal signals
+        // aimed at the daemon don't reach it
+        .process_group(0);
+
+    if let Some(user) = &exec.run_as {
+        if let Some(name) = &user.name {
+            command.env("USER", name).env("LOGNAME", name);
+        }
+        if let Some(home) = &user.home {
+            command.env("HOME", home);
+        }
+
+        // Groups are looked up before forking: the hook below runs in a
+        // copy of a multithreaded process, where reading the group
+        // database could deadlock on a lock another thread held
+        let groups = match &user.name {
+            Some(name) => group_list(name, user.gid)?,
+            None => vec![user.gid],
+        };
+        let (uid, gid) = (user.uid, user.gid);
+        // Drop privileges ourselves: std would otherwise switch uid before
+        // running this hook, after which setgroups is no longer allowed
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setgroups(groups.len(), groups.as_ptr()) != 0
+                    || libc::setgid(gid) != 0
+                    || libc::setuid(uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    command.envs(&exec.env);
+
+    let mut child = command.spawn()?;
+    thread::Builder::new()
+        .name("exec-reaper".to_string())
+        .spawn(move || {
+            let _ = child.wait();
+        })?;
+    Ok(())
+}
@@ -0,0 +1,512 @@
+//! Switching profiles to follow the focused application.
+//!
+//! A [`FocusSource`] reports the app id or window class of each window that
+//! gains focus. A watcher thread matches it against the `[[rules]]` in the
+//! config and switches the active profile of a [`SharedKeyMapper`].
+
+use crate::key_map::{FocusSettings, ProfileSwitch, SourceKind};
+use crate::poll;
+use crate::reload::SharedKeyMapper;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+macro_rules! debug_println {
+    ($($arg:tt)*) => {
+        {
+            #[cfg(debug_assertions)]
+            {
+                eprintln!($($arg)*);
+            }
+        }
+    };
+}
+
+/// How long the file source waits before looking for new lines again.
+const FILE_POLL: Duration = Duration::from_millis(250);
+
+/// How long to wait before reconnecting to a focus source that failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Reports which application has focus.
+pub trait FocusSource: Send {
+    /// Blocks until a window gains focus and returns its app id or class,
+    /// or `None` if none did within about a second, so that the caller can
+    /// check whether to stop.
+    fn next_focus(&mut self) -> io::Result<Option<String>>;
+}
+
+/// Creates the focus source described by a `[focus]` table.
+pub fn source_from_settings(settings: &FocusSettings) -> io::Result<Box<dyn FocusSource>> {
+    Ok(match settings.source {
+        SourceKind::X11 => Box::new(X11Source::new(
+            settings.display.as_deref(),
+            settings.xauthority.as_deref(),
+        )?),
+        SourceKind::Sway | SourceKind::I3 => {
+            Box::new(SwaySource::connect(settings.socket.as_deref())?)
+        }
+        SourceKind::File => {
+            let path = settings
+                .path
+                .as_deref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no path set"))?;
+            Box::new(FileSource::new(path))
+        }
+    })
+}
+
+/// Starts a thread that switches `key_mapper` to the profile of the first
+/// matching rule each time a focus source reports a newly focused
+/// application.
+///
+/// `connect` creates the source, and is called again a few seconds after
+/// the source fails, e.g. because the window manager restarted. Rules are
+/// read from the active mapping on every focus change, so they follow
+/// config reloads. The thread exits once `running` is cleared.
+pub fn watch<F>(
+    mut connect: F,
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>>
+where
+    F: FnMut() -> io::Result<Box<dyn FocusSource>> + Send + 'static,
+{
+    thread::Builder::new()
+        .name("focus".to_string())
+        .spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                let mut source = match connect() {
+                    Ok(source) => source,
+                    Err(e) => {
+                        debug_println!("Could not connect to focus source: {}", e);
+                        pause(&running, RECONNECT_DELAY);
+                        continue;
+                    }
+                };
+
+                while running.load(Ordering::SeqCst) {
+                    match source.next_focus() {
+                        Ok(Some(app)) => follow(&app, &key_mapper),
+                        Ok(None) => {}
+                        Err(e) => {
+                            eprintln!("Focus source stopped: {}", e);
+                            pause(&running, RECONNECT_DELAY);
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+}
+
+/// Sleeps for `delay`, or until `running` is cleared.
+fn pause(running: &AtomicBool, delay: Duration) {
+    let mut left = delay;
+    while !left.is_zero() && running.load(Ordering::SeqCst) {
+        let step = left.min(poll::RUNNING_CHECK);
+        thread::sleep(step);
+        left -= step;
+    }
+}
+
+/// Switches to the profile the rules give for `app`.
+fn follow(app: &str, key_mapper: &SharedKeyMapper) {
+    let (_, current) = key_mapper.current();
+    let Some(focus) = current.focus() else {
+        return;
+    };
+    let profile = focus.profile_for(app);
+    debug_println!("Focused {} -> profile {}", app, profile);
+
+    let switch = ProfileSwitch::Named(profile.to_string());
+    if let Err(e) = key_mapper.switch_profile(&switch) {
+        eprintln!("Could not switch profile: {}", e);
+    }
+}
+
+/// Reads focused application names from a file or FIFO, one per line.
+///
+/// A FIFO is reopened whenever its writer goes away; a regular file is
+/// followed like `tail -f`. Handy for scripting and for testing without a
+/// desktop.
+pub struct FileSource {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> FileSource {
+        FileSource {
+            path: PathBuf::from(path),
+            reader: None,
+        }
+    }
+}
+
+impl FocusSource for FileSource {
+    fn next_focus(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let reader = match &mut self.reader {
+                Some(reader) => reader,
+                None => self.reader.insert(BufReader::new(open(&self.path)?)),
+            };
+            let fd = reader.get_ref().as_raw_fd();
+            if reader.buffer().is_empty() && !poll::readable(fd, poll::RUNNING_CHECK)? {
+                return Ok(None);
+            }
+
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                if is_fifo(&self.path) {
+                    // The writer closed its end; wait for the next one
+                    self.reader = None;
+                    continue;
+                }
+                thread::sleep(FILE_POLL);
+                return Ok(None);
+            }
+
+            let app = line.trim();
+            if !app.is_empty() {
+                return Ok(Some(app.to_string()));
+            }
+        }
+    }
+}
+
+/// Opens a file or FIFO for reading. Opening a FIFO would block until it
+/// has a writer, so that is left to `poll` instead.
+fn open(path: &Path) -> io::Result<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)?;
+    // Reads block again, so a line written in pieces is read whole
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+fn is_fifo(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| m.file_type().is_fifo())
+}
+
+/// Follows `_NET_ACTIVE_WINDOW` on an X11 display through `xprop`.
+pub struct X11Source {
+    display: Option<String>,
+    xauthority: Option<String>,
+    spy: Child,
+    lines: BufReader<ChildStdout>,
+}
+
+impl X11Source {
+    /// Starts watching the display given, or `$DISPLAY` if `None`.
+    pub fn new(display: Option<&str>, xauthority: Option<&str>) -> io::Result<X11Source> {
+        let display = display.map(str::to_string);
+        let xauthority = xauthority.map(str::to_string);
+        let mut spy = xprop(&display, &xauthority)
+            .args(["-root", "-spy", "_NET_ACTIVE_WINDOW"])
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = spy.stdout.take().ok_or_else(|| io::Error::other("no xprop output"))?;
+        Ok(X11Source {
+            display,
+            xauthority,
+            spy,
+            lines: BufReader::new(stdout),
+        })
+    }
+
+    fn window_class(&self, window: &str) -> io::Result<Option<String>> {
+        let output = xprop(&self.display, &self.xauthority)
+            .args(["-id", window, "WM_CLASS"])
+            .output()?;
+        // WM_CLASS(STRING) = "instance", "Class"
+        let output = String::from_utf8_lossy(&output.stdout);
+        let class = output
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .last()
+            .map(str::to_string);
+        Ok(class)
+    }
+}
+
+impl FocusSource for X11Source {
+    fn next_focus(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let fd = self.lines.get_ref().as_raw_fd();
+            if self.lines.buffer().is_empty() && !poll::readable(fd, poll::RUNNING_CHECK)? {
+                return Ok(None);
+            }
+            let mut line = String::new();
+            if self.lines.read_line(&mut line)? == 0 {
+                return Err(io::Error::other("xprop exited"));
+            }
+
+            // _NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007
+            let Some(window) = line.trim().rsplit(' ').next() else {
+                continue;
+            };
+            if !window.starts_with("0x") || window == "0x0" {
+                continue;
+            }
+            if let Some(class) = self.window_class(window)? {
+                return Ok(Some(class));
+            }
+        }
+    }
+}
+
+impl Drop for X11Source {
+    fn drop(&mut self) {
+        let _ = self.spy.kill();
+        let _ = self.spy.wait();
+    }
+}
+
+fn xprop(display: &Option<String>, xauthority: &Option<String>) -> Command {
+    let mut command = Command::new("xprop");
+    command.stdin(Stdio::null());
+    if let Some(display) = display {
+        command.env("DISPLAY", display);
+    }
+    if let Some(xauthority) = xauthority {
+        command.env("XAUTHORITY", xauthority);
+    }
+    command
+}
+
+/// Follows window focus events over the sway or i3 IPC socket.
+pub struct SwaySource {
+    stream: UnixStream,
+}
+
+const IPC_MAGIC: &[u8] = b"i3-ipc";
+const IPC_SUBSCRIBE: u32 = 2;
+// Event replies have the high bit set on top of the event number
+const IPC_EVENT_WINDOW: u32 = 0x8000_0003;
+
+impl SwaySource {
+    /// Connects to `socket`, or to `$SWAYSOCK`, `$I3SOCK` or the first
+    /// socket found under `/run/user` if `None`.
+    pub fn connect(socket: Option<&str>) -> io::Result<SwaySource> {
+        let path = match socket {
+            Some(socket) => PathBuf::from(socket),
+            None => find_ipc_socket()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no sway or i3 socket found"))?,
+        };
+
+        let mut source = SwaySource {
+            stream: UnixStream::connect(&path)?,
+        };
+        source.send(IPC_SUBSCRIBE, br#"["window"]"#)?;
+        let (_, reply) = source.receive()?;
+        if reply.get("success") != Some(&serde_json::Value::Bool(true)) {
+            return Err(io::Error::other("subscribing to window events failed"));
+        }
+        Ok(source)
+    }
+
+    fn send(&mut self, kind: u32, payload: &[u8]) -> io::Result<()> {
+        let mut message = IPC_MAGIC.to_vec();
+        message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(payload);
+        self.stream.write_all(&message)
+    }
+
+    fn receive(&mut self) -> io::Result<(u32, serde_json::Value)> {
+        let mut header = [0u8; 14];
+        self.stream.read_exact(&mut header)?;
+        if &header[..6] != IPC_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad IPC header"));
+        }
+        let len = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+        let kind = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+
+        let mut payload = vec![0u8; len];
+        self.stream.read_exact(&mut payload)?;
+        let value = serde_json::from_slice(&payload)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok((kind, value))
+    }
+}
+
+impl FocusSource for SwaySource {
+    fn next_focus(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if !poll::readable(self.stream.as_raw_fd(), poll::RUNNING_CHECK)? {
+                return Ok(None);
+            }
+            let (kind, event) = self.receive()?;
+            if kind != IPC_EVENT_WINDOW || event.get("change").and_then(serde_json::Value::as_str) != Some("focus") {
+                continue;
+            }
+            let Some(container) = event.get("container") else {
+                continue;
+            };
+
+            // Wayland windows have an app id, X11 ones a window class
+            let app = container
+                .get("app_id")
+                .and_then(serde_json::Value::as_str)
+                .or_else(|| {
+                    container
+                        .get("window_properties")
+                        .and_then(|props| props.get("class"))
+                        .and_then(serde_json::Value::as_str)
+                });
+            if let Some(app) = app {
+                return Ok(Some(app.to_string()));
+            }
+        }
+    }
+}
+
+/// Finds a sway or i3 socket for a daemon that isn't running in the
+/// user's session.
+fn find_ipc_socket() -> Option<PathBuf> {
+    for var in ["SWAYSOCK", "I3SOCK"] {
+        if let Some(path) = std::env::var_os(var) {
+            return Some(PathBuf::from(path));
+        }
+    }
+
+    for user in std::fs::read_dir("/run/user").ok()?.flatten() {
+        let dirs = [user.path(), user.path().join("i3")];
+        for dir in dirs.iter().filter_map(|dir| std::fs::read_dir(dir).ok()) {
+            for entry in dir.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with("sway-ipc.") || name.starts_with("ipc-socket.") {
+                    return Some(entry.path());
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key_map::KeyMapper;
+    use std::ffi::CString;
+    use std::time::Instant;
+
+    const CONFIG: &str = r#"
+[profiles.browser.keys]
+1 = "A"
+
+[profiles.wow.keys]
+1 = "B"
+
+[focus]
+source = "file"
+path = "/dev/null"
+fallback = "wow"
+
+[[rules]]
+class = "firefox"
+profile = "browser"
+
+[[rules]]
+class = "Firefox"
+profile = "wow"
+"#;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("naga-focus-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let key_mapper = KeyMapper::from_toml_str(CONFIG).unwrap();
+        let focus = key_mapper.focus().unwrap();
+        assert_eq!(focus.profile_for("FIREFOX"), "browser");
+        assert_eq!(focus.profile_for("kitty"), "wow");
+
+        let shared = SharedKeyMapper::new(key_mapper);
+        follow("firefox", &shared);
+        assert_eq!(shared.current().1.profile().name(), "browser");
+        follow("kitty", &shared);
+        assert_eq!(shared.current().1.profile().name(), "wow");
+    }
+
+    #[test]
+    fn file_source_follows_appended_lines() {
+        let path = temp_path("file");
+        std::fs::write(&path, "firefox\n\n  kitty  \n").unwrap();
+        let mut source = FileSource::new(path.to_str().unwrap());
+        assert_eq!(source.next_focus().unwrap().as_deref(), Some("firefox"));
+        assert_eq!(source.next_focus().unwrap().as_deref(), Some("kitty"));
+        assert_eq!(source.next_focus().unwrap(), None);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "wow.exe").unwrap();
+        assert_eq!(source.next_focus().unwrap().as_deref(), Some("wow.exe"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_source_waits_for_fifo_writers() {
+        let path = temp_path("fifo");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let mut source = FileSource::new(path.to_str().unwrap());
+
+        // No writer yet: the source gives up after a while instead of
+        // blocking in open
+        let start = Instant::now();
+        assert_eq!(source.next_focus().unwrap(), None);
+        assert!(start.elapsed() < poll::RUNNING_CHECK * 2);
+
+        for app in ["firefox", "kitty"] {
+            let mut writer = OpenOptions::new().write(true).open(&path).unwrap();
+            writeln!(writer, "{}", app).unwrap();
+            drop(writer);
+            assert_eq!(source.next_focus().unwrap().as_deref(), Some(app));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn watch_stops_when_running_is_cleared() {
+        let path = temp_path("stop");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        let key_mapper = Arc::new(SharedKeyMapper::new(KeyMapper::from_toml_str(CONFIG).unwrap()));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let source_path = path.clone();
+        let connect = move || {
+            let source: Box<dyn FocusSource> = Box::new(FileSource::new(source_path.to_str().unwrap()));
+            Ok(source)
+        };
+        let handle = watch(connect, key_mapper, running.clone()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        running.store(false, Ordering::SeqCst);
+        handle.join().unwrap();
+        assert!(start.elapsed() < poll::RUNNING_CHECK * 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+//! Noticing the Naga being plugged in.
+//!
+//! While no Naga is attached the run loop waits for kernel uevents instead
+//! of rescanning `/dev/input`, and looks for the mouse again only when a new
+//! input event node appears. [`Uevents::synthetic`] gives a source that is
+//! fed by hand, to exercise attach and detach without hardware.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// A device event from the kernel, such as an input node appearing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Uevent {
+    /// `add`, `remove`, `change`, ...
+    pub action: String,
+    /// The device's path under `/sys`, e.g. `/devices/.../input/input7/event5`.
+    pub devpath: String,
+    pub subsystem: String,
+    /// The node's path under `/dev`, e.g. `input/event5`, if it has one.
+    pub devname: Option<String>,
+}
+
+impl Uevent {
+    /// Parses a message in the kernel's format: `action@devpath`, then
+    /// `KEY=value` fields, all separated by NULs.
+    pub fn parse(message: &[u8]) -> Option<Uevent> {
+        let mut fields = message
+            .split(|&b| b == 0)
+            .filter(|field| !field.is_empty())
+            .map(String::from_utf8_lossy);
+        let header = fields.next()?;
+        let (action, devpath) = header.split_once('@')?;
+
+        let mut event = Uevent {
+            action: action.to_string(),
+            devpath: devpath.to_string(),
+            subsystem: String::new(),
+            devname: None,
+        };
+        for field in fields {
+            match field.split_once('=') {
+                Some(("ACTION", action)) => event.action = action.to_string(),
+                Some(("SUBSYSTEM", subsystem)) => event.subsystem = subsystem.to_string(),
+                Some(("DEVNAME", devname)) => event.devname = Some(devname.to_string()),
+                _ => {}
+            }
+        }
+        Some(event)
+    }
+
+    /// Writes the event in the kernel's format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut message = format!("{}@{}\0", self.action, self.devpath);
+        message.push_str(&format!("ACTION={}\0", self.action));
+        message.push_str(&format!("DEVPATH={}\0", self.devpath));
+        message.push_str(&format!("SUBSYSTEM={}\0", self.subsystem));
+        if let Some(devname) = &self.devname {
+            message.push_str(&format!("DEVNAME={}\0", devname));
+        }
+        message.into_bytes()
+    }
+
+    /// Whether a `/dev/input/event*` node appeared, which may be the Naga.
+    pub fn is_event_node_added(&self) -> bool {
+        self.action == "add" && self.is_event_node()
+    }
+
+    /// Whether a `/dev/input/event*` node went away.
+    pub fn is_event_node_removed(&self) -> bool {
+        self.action == "remove" && self.is_event_node()
+    }
+
+    fn is_event_node(&self) -> bool {
+        self.subsystem == "input"
+            && self
+                .devname
+                .as_deref()
+                .is_some_and(|devname| devname.starts_with("input/event"))
+    }
+}
+
+/// A socket that uevents arrive on, one per datagram.
+pub struct Uevents {
+    fd: RawFd,
+}
+
+// Multicast group the kernel sends uevents to (udev's own is 2)
+const KERNEL_GROUP: u32 = 1;
+
+impl Uevents {
+    /// Listens for the kernel's uevents. Usually needs root.
+    pub fn kernel() -> io::Result<Uevents> {
+        let fd = check(unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        })?;
+        let uevents = Uevents { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_GROUP;
+        check(unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        })?;
+        Ok(uevents)
+    }
+
+    /// Creates a source fed only through the returned [`UeventSender`].
+    pub fn synthetic() -> io::Result<(Uevents, UeventSender)> {
+        let mut fds = [0; 2];
+        check(unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        })?;
+        Ok((Uevents { fd: fds[0] }, UeventSender { fd: fds[1] }))
+    }
+
+    /// Returns every event waiting, without blocking. Messages that aren't
+    /// uevents are skipped.
+    ///
+    /// If the kernel had to drop events because they weren't read in time,
+    /// everything waiting is still consumed but `ENOBUFS` is returned.
+    pub fn read(&mut self) -> io::Result<Vec<Uevent>> {
+        let mut events = Vec::new();
+        let mut overflowed = false;
+        let mut buf = [0u8; 8192];
+        loop {
+            let len = unsafe {
+                libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                match e.raw_os_error() {
+                    Some(libc::EAGAIN) if overflowed => {
+                        return Err(io::Error::from_raw_os_error(libc::ENOBUFS))
+                    }
+                    Some(libc::EAGAIN) => return Ok(events),
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ENOBUFS) => {
+                        overflowed = true;
+                        continue;
+                    }
+                    _ => return Err(e),
+                }
+            }
+            if let Some(event) = Uevent::parse(&buf[..len as usize]) {
+                events.push(event);
+            }
+        }
+    }
+}
+
+impl AsRawFd for Uevents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Uevents {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Feeds events to a source made by [`Uevents::synthetic`].
+pub struct UeventSender {
+    fd: RawFd,
+}
+
+impl UeventSender {
+    pub fn send(&self, event: &Uevent) -> io::Result<()> {
+        let message = event.to_bytes();
+        check(unsafe {
+            libc::send(self.fd, message.as_ptr() as *const libc::c_void, message.len(), 0) as libc::c_int
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for UeventSender {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(action: &str, devname: Option<&str>) -> Uevent {
+        Uevent {
+            action: action.to_string(),
+            devpath: "/devices/pci0000:00/usb1/1-1/input/input7/event5".to_string(),
+            subsystem: "input".to_string(),
+            devname: devname.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_kernel_messages() {
+        let message = b"add@/devices/virtual/input/input7/event5\0ACTION=add\0\
+            DEVPATH=/devices/virtual/input/input7/event5\0SUBSYSTEM=input\0\
+            MAJOR=13\0MINOR=69\0DEVNAME=input/event5\0SEQNUM=4321\0";
+        let event = Uevent::parse(message).unwrap();
+        assert_eq!(event.action, "add");
+        assert_eq!(event.devpath, "/devices/virtual/input/input7/event5");
+        assert_eq!(event.subsystem, "input");
+        assert_eq!(event.devname.as_deref(), Some("input/event5"));
+
+        // udev's own messages start with a binary header instead
+        assert_eq!(Uevent::parse(b"libudev\0\xfe\xed\xca\xfe"), None);
+        assert_eq!(Uevent::parse(b""), None);
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let event = event("add", Some("input/event5"));
+        assert_eq!(Uevent::parse(&event.to_bytes()), Some(event));
+    }
+
+    #[test]
+    fn only_event_nodes_count() {
+        assert!(event("add", Some("input/event5")).is_event_node_added());
+        assert!(!event("add", Some("input/mouse2")).is_event_node_added());
+        assert!(!event("add", None).is_event_node_added());
+        assert!(!event("remove", Some("input/event5")).is_event_node_added());
+        assert!(event("remove", Some("input/event5")).is_event_node_removed());
+
+        let mut other = event("add", Some("input/event5"));
+        other.subsystem = "hidraw".to_string();
+        assert!(!other.is_event_node_added());
+    }
+
+    #[test]
+    fn synthetic_source_delivers_what_is_sent() {
+        let (mut uevents, sender) = Uevents::synthetic().unwrap();
+        assert_eq!(uevents.read().unwrap(), Vec::new());
+
+        let events = [event("add", Some("input/event5")), event("remove", None)];
+        for event in &events {
+            sender.send(event).unwrap();
+        }
+        assert_eq!(uevents.read().unwrap(), events);
+        assert_eq!(uevents.read().unwrap(), Vec::new());
+    }
+}
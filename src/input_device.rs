@@ -1,15 +1,399 @@
-use uinput::event::Keyboard::All;
-use uinput::{Device, Error};
+use crate::error::Error;
+use crate::key_codes::{self, Class};
+use crate::key_map::{Chord, Input, InputMouse, Scroll, MOUSE_BUTTONS};
+use crate::naga::Event;
+use std::ffi::CString;
+use std::io;
+use std::path::PathBuf;
+use std::{mem, slice};
+use uinput::event::Code;
+use uinput::Device;
+use uinput_sys::{
+    ui_dev_create, ui_set_absbit, ui_set_evbit, ui_set_keybit, ui_set_ledbit, ui_set_mscbit,
+    ui_set_relbit, uinput_user_dev, ABS_HAT0X, ABS_HAT0Y, EV_ABS, EV_KEY, EV_LED, EV_MSC, EV_REL,
+    LED_MAX, MSC_SCAN, REL_HWHEEL, REL_WHEEL, REL_X, REL_Y,
+};
 
-pub fn create() -> Result<Device, String> {
-    create_device().map_err(|e| format!("{}", e))
+/// Name of the virtual keyboard unless `[virtual_device]` says otherwise.
+pub const DEFAULT_NAME: &str = "razer-naga-virtual-keyboard";
+
+/// Longest device name the kernel keeps, in bytes.
+pub const MAX_NAME_LEN: usize = uinput_sys::UINPUT_MAX_NAME_SIZE as usize - 1;
+
+/// Bus types `[virtual_device]` can name, with their `BUS_*` values.
+pub const BUSES: &[(&str, u16)] = &[
+    ("pci", 0x01),
+    ("usb", 0x03),
+    ("bluetooth", 0x05),
+    ("virtual", 0x06),
+    ("i8042", 0x11),
+    ("host", 0x19),
+];
+
+const GAMEPAD_NAME: &str = "razer-naga-virtual-gamepad";
+
+const POINTER_NAME: &str = "razer-naga-virtual-pointer";
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// The name and ids the virtual keyboard shows the system, so that hwdb
+/// entries, libinput quirks and compositor rules can match it.
+///
+/// The defaults, the old fixed name and all ids zero, never change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtualDeviceSettings {
+    pub(crate) name: String,
+    pub(crate) vendor: u16,
+    pub(crate) product: u16,
+    pub(crate) version: u16,
+    // One of the kernel's `BUS_*` values
+    pub(crate) bus: u16,
 }
+impl Default for VirtualDeviceSettings {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_NAME.to_string(),
+            vendor: 0,
+            product: 0,
+            version: 0,
+            bus: 0,
+        }
+    }
+}
+impl VirtualDeviceSettings {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn vendor(&self) -> u16 {
+        self.vendor
+    }
 
-fn create_device() -> Result<Device, Error> {
-    let device = uinput::open("/dev/uinput")?
-        .name("razer-naga-virtual-keyboard")?
-        .event(All)?
-        .create()?;
+    pub fn product(&self) -> u16 {
+        self.product
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn bus(&self) -> u16 {
+        self.bus
+    }
+}
+
+/// The virtual devices remapped input is sent from: a keyboard, a pointer
+/// while the config clicks, scrolls or moves it or grabs the pointer
+/// interface, and a gamepad while the config presses any `Pad::` button
+/// or other `BTN_*` code.
+pub struct Output {
+    keyboard: Device,
+    pointer: Option<Device>,
+    gamepad: Option<Device>,
+    // Held d-pad buttons, which together set the hat's position
+    dpad: Vec<u16>,
+}
+
+/// Creates the virtual keyboard the remapped keys are sent from, named as
+/// `settings` say. Only keys are advertised on it, so the system never
+/// takes it for a mouse.
+pub fn create(settings: &VirtualDeviceSettings) -> Result<Output, Error> {
+    let keyboard = create_device(settings, setup_keyboard).map_err(uinput_error)?;
+    Ok(Output {
+        keyboard,
+        pointer: None,
+        gamepad: None,
+        dpad: Vec::new(),
+    })
+}
+
+fn uinput_error(e: io::Error) -> Error {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied {
+            path: PathBuf::from(UINPUT_PATH),
+        },
+        _ => Error::UinputUnavailable(e),
+    }
+}
+
+impl Output {
+    /// Creates the virtual gamepad, or removes it. Should it fail to appear
+    /// the problem is only reported, and `Pad::` buttons do nothing.
+    pub fn set_gamepad(&mut self, enabled: bool) {
+        if !enabled {
+            self.gamepad = None;
+            self.dpad.clear();
+        } else if self.gamepad.is_none() {
+            let settings = VirtualDeviceSettings {
+                name: GAMEPAD_NAME.to_string(),
+                ..VirtualDeviceSettings::default()
+            };
+            match create_device(&settings, setup_gamepad) {
+                Ok(gamepad) => self.gamepad = Some(gamepad),
+                Err(e) => eprintln!("Pad:: buttons disabled: {}", uinput_error(e)),
+            }
+        }
+    }
+
+    /// Creates the virtual pointer, or removes it. Should it fail to appear
+    /// the problem is only reported, and pointer output is dropped.
+    pub fn set_pointer(&mut self, enabled: bool) {
+        if !enabled {
+            self.pointer = None;
+        } else if self.pointer.is_none() {
+            let settings = VirtualDeviceSettings {
+                name: POINTER_NAME.to_string(),
+                ..VirtualDeviceSettings::default()
+            };
+            match create_device(&settings, setup_pointer) {
+                Ok(pointer) => self.pointer = Some(pointer),
+                Err(e) => eprintln!("Pointer output disabled: {}", uinput_error(e)),
+            }
+        }
+    }
+
+    /// Presses a key or button on whichever device has it.
+    pub fn press(&mut self, input: &Input) -> Result<(), uinput::Error> {
+        self.key(input.code(), 1)
+    }
+
+    /// Releases a key or button on whichever device has it.
+    pub fn release(&mut self, input: &Input) -> Result<(), uinput::Error> {
+        self.key(input.code(), 0)
+    }
+
+    fn key(&mut self, code: libc::c_int, value: i32) -> Result<(), uinput::Error> {
+        match key_codes::class(code as u16) {
+            Class::Key => self.keyboard.write(EV_KEY, code, value),
+            Class::Mouse => self.write_pointer(EV_KEY, code, value),
+            Class::Button => self.pad(code as u16, value),
+        }
+    }
+
+    /// Writes a raw event: motion, the wheel and mouse buttons to the
+    /// pointer, anything else to the keyboard.
+    pub fn write(&mut self, kind: libc::c_int, code: libc::c_int, value: i32) -> Result<(), uinput::Error> {
+        if is_pointer_event(kind, code) {
+            self.write_pointer(kind, code, value)
+        } else {
+            self.keyboard.write(kind, code, value)
+        }
+    }
+
+    /// Writes a raw event to the pointer, if there is one.
+    pub fn write_pointer(&mut self, kind: libc::c_int, code: libc::c_int, value: i32) -> Result<(), uinput::Error> {
+        match &mut self.pointer {
+            Some(pointer) => pointer.write(kind, code, value),
+            None => Ok(()),
+        }
+    }
+
+    /// Ends the report on every device.
+    pub fn synchronize(&mut self) -> Result<(), uinput::Error> {
+        self.keyboard.synchronize()?;
+        if let Some(pointer) = &mut self.pointer {
+            pointer.synchronize()?;
+        }
+        match &mut self.gamepad {
+            Some(gamepad) => gamepad.synchronize(),
+            None => Ok(()),
+        }
+    }
+
+    fn pad(&mut self, code: u16, value: i32) -> Result<(), uinput::Error> {
+        let Some(gamepad) = &mut self.gamepad else {
+            return Ok(());
+        };
+        let Some((axis, _)) = hat(code) else {
+            return gamepad.write(EV_KEY, code as libc::c_int, value);
+        };
+        self.dpad.retain(|&held| held != code);
+        if value == 1 {
+            self.dpad.push(code);
+        }
+        // Opposite directions held together cancel out
+        let position = self
+            .dpad
+            .iter()
+            .filter_map(|&held| hat(held))
+            .filter(|&(held_axis, _)| held_axis == axis)
+            .map(|(_, direction)| direction)
+            .sum::<i32>();
+        gamepad.write(EV_ABS, axis, position)
+    }
+}
+
+/// Whether an event belongs on the virtual pointer.
+pub(crate) fn is_pointer_event(kind: libc::c_int, code: libc::c_int) -> bool {
+    match kind {
+        EV_REL => true,
+        EV_KEY => key_codes::class(code as u16) == Class::Mouse,
+        _ => false,
+    }
+}
+
+/// The hat axis and direction a `BTN_DPAD_*` code stands for.
+fn hat(code: u16) -> Option<(libc::c_int, i32)> {
+    match code {
+        0x220 => Some((ABS_HAT0Y, -1)),
+        0x221 => Some((ABS_HAT0Y, 1)),
+        0x222 => Some((ABS_HAT0X, -1)),
+        0x223 => Some((ABS_HAT0X, 1)),
+        _ => None,
+    }
+}
+
+// uinput's builder only advertises the keys its own enums know about, so
+// devices are set up by hand to cover every code in `key_codes`
+fn create_device(
+    settings: &VirtualDeviceSettings,
+    setup: fn(libc::c_int, &mut uinput_user_dev) -> io::Result<()>,
+) -> io::Result<Device> {
+    let path = CString::new(UINPUT_PATH)?;
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_NONBLOCK) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut def: uinput_user_dev = unsafe { mem::zeroed() };
+    // The last byte stays zero to end the name
+    for (dst, &src) in def.name[..MAX_NAME_LEN].iter_mut().zip(settings.name.as_bytes()) {
+        *dst = src as libc::c_char;
+    }
+    def.id.bustype = settings.bus;
+    def.id.vendor = settings.vendor;
+    def.id.product = settings.product;
+    def.id.version = settings.version;
+    match setup(fd, &mut def).and_then(|()| register(fd, &def)) {
+        Ok(()) => Ok(Device::new(fd)),
+        Err(e) => {
+            unsafe { libc::close(fd) };
+            Err(e)
+        }
+    }
+}
+
+fn setup_keyboard(fd: libc::c_int, _def: &mut uinput_user_dev) -> io::Result<()> {
+    check(unsafe { ui_set_evbit(fd, EV_KEY) })?;
+    // Unnamed codes too, so that `code:N` reaches the system for any key
+    for code in key_codes::codes_of(Class::Key) {
+        check(unsafe { ui_set_keybit(fd, code as libc::c_int) })?;
+    }
+    // So scan codes and LED state passed through from the mouse arrive
+    check(unsafe { ui_set_evbit(fd, EV_MSC) })?;
+    check(unsafe { ui_set_mscbit(fd, MSC_SCAN) })?;
+    check(unsafe { ui_set_evbit(fd, EV_LED) })?;
+    for led in 0..=LED_MAX {
+        check(unsafe { ui_set_ledbit(fd, led) })?;
+    }
+    Ok(())
+}
+
+fn setup_pointer(fd: libc::c_int, _def: &mut uinput_user_dev) -> io::Result<()> {
+    check(unsafe { ui_set_evbit(fd, EV_KEY) })?;
+    for (_, code) in MOUSE_BUTTONS {
+        check(unsafe { ui_set_keybit(fd, *code as libc::c_int) })?;
+    }
+    // High resolution scrolling is left out: clients that see it ignore
+    // REL_WHEEL, and older kernels don't send it
+    check(unsafe { ui_set_evbit(fd, EV_REL) })?;
+    for code in [REL_X, REL_Y, REL_WHEEL, REL_HWHEEL] {
+        check(unsafe { ui_set_relbit(fd, code) })?;
+    }
+    check(unsafe { ui_set_evbit(fd, EV_MSC) })?;
+    check(unsafe { ui_set_mscbit(fd, MSC_SCAN) })?;
+    Ok(())
+}
+
+fn setup_gamepad(fd: libc::c_int, def: &mut uinput_user_dev) -> io::Result<()> {
+    check(unsafe { ui_set_evbit(fd, EV_KEY) })?;
+    // Every button besides the mouse's, for `code:N`
+    for code in key_codes::codes_of(Class::Button).filter(|&code| hat(code).is_none()) {
+        check(unsafe { ui_set_keybit(fd, code as libc::c_int) })?;
+    }
+    check(unsafe { ui_set_evbit(fd, EV_ABS) })?;
+    for axis in [ABS_HAT0X, ABS_HAT0Y] {
+        check(unsafe { ui_set_absbit(fd, axis) })?;
+        def.absmin[axis as usize] = -1;
+        def.absmax[axis as usize] = 1;
+    }
+    Ok(())
+}
+
+fn register(fd: libc::c_int, def: &uinput_user_dev) -> io::Result<()> {
+    let bytes = unsafe {
+        slice::from_raw_parts(
+            def as *const uinput_user_dev as *const u8,
+            mem::size_of::<uinput_user_dev>(),
+        )
+    };
+    let written = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    check(unsafe { ui_dev_create(fd) })
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Presses the keys of a chord in order and synchronizes.
+pub(crate) fn press(device: &mut Output, chord: &Chord) -> Result<(), uinput::Error> {
+    for input in chord.iter() {
+        device.press(input)?;
+    }
+    device.synchronize()
+}
+
+/// Releases the keys of a chord in reverse order and synchronizes.
+pub(crate) fn release(device: &mut Output, chord: &Chord) -> Result<(), uinput::Error> {
+    for input in chord.iter().rev() {
+        device.release(input)?;
+    }
+    device.synchronize()
+}
+
+/// Presses and releases a chord as two separate reports.
+pub(crate) fn tap(device: &mut Output, chord: &Chord) -> Result<(), uinput::Error> {
+    press(device, chord)?;
+    release(device, chord)
+}
+
+/// Sends an event from the mouse on unchanged.
+pub(crate) fn forward(device: &mut Output, event: &Event) -> Result<(), uinput::Error> {
+    device.write(event.kind as libc::c_int, event.code as libc::c_int, event.value)
+}
+
+/// Sends an event from the pointer interface on unchanged.
+pub(crate) fn forward_pointer(device: &mut Output, event: &Event) -> Result<(), uinput::Error> {
+    device.write_pointer(event.kind as libc::c_int, event.code as libc::c_int, event.value)
+}
+
+/// Turns the wheel and synchronizes.
+pub(crate) fn scroll(device: &mut Output, scroll: &Scroll) -> Result<(), uinput::Error> {
+    let (axis, amount) = scroll.event();
+    device.write_pointer(EV_REL, axis, amount)?;
+    device.synchronize()
+}
+
+/// Moves the pointer by `x` and `y` and synchronizes.
+pub(crate) fn move_pointer(device: &mut Output, x: i32, y: i32) -> Result<(), uinput::Error> {
+    device.write_pointer(EV_REL, REL_X, x)?;
+    device.write_pointer(EV_REL, REL_Y, y)?;
+    device.synchronize()
+}
 
-    Ok(device)
+/// Clicks a mouse button twice, each press and release its own report.
+pub(crate) fn double_click(device: &mut Output, button: InputMouse) -> Result<(), uinput::Error> {
+    for _ in 0..2 {
+        device.press(&Input::InputMouse(button))?;
+        device.synchronize()?;
+        device.release(&Input::InputMouse(button))?;
+        device.synchronize()?;
+    }
+    Ok(())
 }
@@ -3,111 +3,2308 @@
 //! Defines the key mapping structure and handles loading custom
 //! mappings from TOML configuration files.
 
-use serde::{de::Error, Deserialize};
+use crate::device::{self, DeviceSettings};
+use crate::exec::RunAs;
+use crate::input_device::{self, VirtualDeviceSettings};
+use crate::key_codes;
+use crate::layout::{self, Layout, TextSettings, UnicodeEntry};
+use regex::Regex;
+use serde::de::{value::MapAccessDeserializer, Error, MapAccess, SeqAccess, Visitor};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use std::ffi::c_int;
+use std::fmt;
 use std::fs;
-use std::{collections::HashMap, ops::Deref};
+use std::sync::Arc;
+use std::time::Duration;
+use std::collections::BTreeMap;
+use std::{collections::HashMap, ops::Deref, str::FromStr};
 use uinput::event::{
-    keyboard::{Key, KeyPad},
+    keyboard::{Key, KeyPad, Misc},
     Release,
 };
 use uinput::event::{Code, Kind, Press};
 
 /// Configuration for mapping Naga side buttons to keyboard keys.
 ///
-/// Stores the mapping for all 12 side buttons (indices 0-11).
+/// Holds one or more named [`Profile`]s, of which one is active at a time.
 /// Default mapping is keys 1-0, Minus, and Equal.
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct KeyMapper {
-    pub(crate) keys: [Input; 12],
+    // The top-level profile first, then `[profiles.<name>]` in name order
+    pub(crate) profiles: Vec<Profile>,
+    pub(crate) active: usize,
+    pub(crate) macros: HashMap<String, Arc<Macro>>,
+    pub(crate) text: TextSettings,
+    // Run after every profile switch, with the new name in `NAGA_PROFILE`
+    pub(crate) on_profile_switch: Option<Exec>,
+    pub(crate) focus: Option<FocusSettings>,
+    pub(crate) control: ControlSettings,
+    pub(crate) device: DeviceSettings,
+    // `[buttons]` and `[wheel]`, keyed by their button index; the same in
+    // every profile
+    pub(crate) pointer: HashMap<usize, Action>,
+    pub(crate) virtual_device: VirtualDeviceSettings,
+}
+impl Default for KeyMapper {
+    fn default() -> Self {
+        Self {
+            profiles: vec![Profile::default()],
+            active: 0,
+            macros: HashMap::new(),
+            text: TextSettings::default(),
+            on_profile_switch: None,
+            focus: None,
+            control: ControlSettings::default(),
+            device: DeviceSettings::default(),
+            pointer: HashMap::new(),
+            virtual_device: VirtualDeviceSettings::default(),
+        }
+    }
+}
+impl KeyMapper {
+    /// Loads key mappings from a TOML configuration file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the TOML config file
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(KeyMapper)` - Successfully loaded configuration
+    /// * `Err(Error::Config)` - Every problem found reading or parsing the file
+    ///
+    /// # Example Config
+    ///
+    /// ```toml
+    /// on_profile_switch = { exec = "notify-send", args = ["Naga profile changed"] }
+    ///
+    /// [keys]
+    /// "1" = "F1"
+    /// "2" = "F2"
+    /// "3" = { turbo = "A", rate_hz = 20 }
+    /// "5" = "Profile::next"
+    /// "6" = "KP::_1"
+    /// "7" = "LeftControl+LeftShift+T"
+    /// "8" = "Macro::build"
+    /// "9" = { tap = "Esc", hold = "LeftControl", hold_ms = 200 }
+    /// "4" = { exec = "notify-send", args = ["Naga"], user = "me" }
+    /// "10" = { taps = ["F1", "F2", "F3"], window_ms = 250 }
+    /// "11" = "text:¯\\_(ツ)_/¯"
+    /// "12" = "Layer::fn"
+    ///
+    /// [macros.build]
+    /// steps = [{ tap = "LeftControl+S" }, { delay_ms = 30 }, { text = "make" }]
+    ///
+    /// [layers.fn.keys]
+    /// "1" = "F13"
+    /// "2" = { toggle = "W" }
+    ///
+    /// [profiles.wow.keys]
+    /// "1" = "_1"
+    /// "12" = "Profile::default"
+    ///
+    /// [focus]
+    /// source = "sway"
+    ///
+    /// [[rules]]
+    /// class = "wow.exe"
+    /// profile = "wow"
+    ///
+    /// [text]
+    /// layout = "us"
+    /// unicode = "ctrl-shift-u"
+    ///
+    /// [buttons]
+    /// Middle = "LeftControl+W"
+    ///
+    /// [wheel]
+    /// left = "KEY_BACK"
+    /// right = "KEY_FORWARD"
+    ///
+    /// [device]
+    /// preset = "naga-trinity"
+    ///
+    /// [virtual_device]
+    /// name = "naga-keys"
+    /// vendor = "1532"
+    /// bus = "usb"
+    /// ```
+    pub fn read_from_file(path: &str) -> Result<KeyMapper, crate::Error> {
+        let contents = fs::read_to_string(path).map_err(|e| ConfigErrors {
+            file: Some(path.to_string()),
+            errors: vec![ConfigError::new(e.to_string())],
+        })?;
+        let key_mapper = KeyMapper::from_toml_str(&contents).map_err(|mut errors| {
+            errors.file = Some(path.to_string());
+            errors
+        })?;
+        Ok(key_mapper)
+    }
+
+    /// Loads key mappings from the text of a TOML config, reporting every
+    /// problem found rather than just the first.
+    pub fn from_toml_str(contents: &str) -> Result<KeyMapper, ConfigErrors> {
+        let table = match contents.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => unreachable!("a TOML document is a table"),
+            Err(e) => {
+                let mut error = ConfigError::new(e.to_string());
+                if let Some((line, column)) = e.line_col() {
+                    // The message ends with the line we report separately
+                    let suffix = format!(" at line {}", line + 1);
+                    if let Some(message) = error.message.strip_suffix(&suffix) {
+                        error.message = message.to_string();
+                    }
+                    error.line = Some(line + 1);
+                    error.column = Some(column + 1);
+                }
+                return Err(ConfigErrors {
+                    file: None,
+                    errors: vec![error],
+                });
+            }
+        };
+
+        let mut problems = Problems {
+            source: contents,
+            errors: Vec::new(),
+        };
+        let config = Config::read(&mut problems, table);
+        let key_mapper = KeyMapper::from_config(&mut problems, config);
+
+        if problems.errors.is_empty() {
+            return Ok(key_mapper);
+        }
+        // Errors are found table by table; report them in file order
+        problems
+            .errors
+            .sort_by_key(|error| (error.line.is_none(), error.line, error.column));
+        Err(ConfigErrors {
+            file: None,
+            errors: problems.errors,
+        })
+    }
+
+    fn from_config(problems: &mut Problems, config: Config) -> KeyMapper {
+        let mut key_mapper = KeyMapper::default();
+
+        if let Some(text) = config.text {
+            if let Some(name) = text.layout {
+                match layout::from_name(&name) {
+                    Some(layout) => key_mapper.text.layout = layout,
+                    None => problems.add(
+                        &["text", "layout"],
+                        ConfigError::new(format!("Unknown layout: {}", name)),
+                    ),
+                }
+            }
+            if let Some(unicode) = text.unicode {
+                key_mapper.text.unicode = unicode;
+            }
+        }
+
+        for (name, macro_) in config.macros {
+            if let Err(e) = macro_.validate(&key_mapper.text) {
+                problems.add(&["macros", &name], ConfigError::new(e));
+            }
+            key_mapper.macros.insert(name, Arc::new(macro_));
+        }
+
+        match config.on_profile_switch {
+            Some(Action::Exec(exec)) => key_mapper.on_profile_switch = Some(exec),
+            Some(_) => problems.add(
+                &["on_profile_switch"],
+                ConfigError::new("`on_profile_switch` must be an exec table"),
+            ),
+            None => {}
+        }
+
+        // Button numbers are checked against the device's buttons
+        if let Some(device) = config.device {
+            key_mapper.device = read_device(problems, device);
+        }
+
+        // Every profile name is known before any binding is checked, so
+        // profiles can switch to each other
+        let top_level = ProfileConfig {
+            keys: config.keys,
+            layers: config.layers,
+        };
+        let mut profiles = vec![(DEFAULT_PROFILE.to_string(), top_level)];
+        for (name, profile) in config.profiles {
+            if name == DEFAULT_PROFILE || !matches!(ProfileSwitch::from_name(&name), ProfileSwitch::Named(_)) {
+                problems.add(
+                    &["profiles", &name],
+                    ConfigError::new(format!("Profile name is reserved: {}", name)),
+                );
+                continue;
+            }
+            profiles.push((name, profile));
+        }
+        key_mapper.profiles = profiles
+            .iter()
+            .map(|(name, _)| Profile::named(name))
+            .collect();
+
+        for (index, (name, config)) in profiles.into_iter().enumerate() {
+            key_mapper.profiles[index] = key_mapper.read_profile(problems, &name, config);
+        }
+
+        if let Some(control) = config.control {
+            key_mapper.control = ControlSettings {
+                enabled: control.enabled.unwrap_or(true),
+                socket: control.socket.unwrap_or_else(|| DEFAULT_CONTROL_SOCKET.to_string()),
+                group: control.group,
+            };
+        }
+
+        if let Some(virtual_device) = config.virtual_device {
+            key_mapper.virtual_device = read_virtual_device(problems, virtual_device);
+        }
+
+        // Bindings outside the side buttons may use the default profile's
+        // layers
+        let default_profile = key_mapper.profiles[0].clone();
+        let pointer_tables = [
+            ("buttons", config.buttons, parse_mouse_button as fn(&str) -> Result<usize, ConfigError>),
+            ("wheel", config.wheel, parse_wheel_direction),
+        ];
+        for (table, keys, parse) in pointer_tables {
+            if !keys.is_empty() && key_mapper.device.pointer_matcher().is_empty() {
+                let message = format!(
+                    "`[{}]` needs the pointer interface described by `pointer_phys` or `pointer_path` in `[device]`",
+                    table
+                );
+                problems.add(&[table], ConfigError::new(message));
+            }
+            let buttons = key_mapper.read_buttons(problems, &default_profile, &[table], keys, parse);
+            key_mapper.pointer.extend(buttons);
+        }
+
+        key_mapper.focus = match config.focus {
+            Some(focus) => Some(key_mapper.read_focus(problems, focus, config.rules)),
+            None if !config.rules.is_empty() => {
+                problems.add(
+                    &["rules"],
+                    ConfigError::new("`[[rules]]` need a `[focus]` table with a source"),
+                );
+                None
+            }
+            None => None,
+        };
+
+        key_mapper
+    }
+
+    fn read_focus(&self, problems: &mut Problems, config: FocusConfig, rules: Vec<Rule>) -> FocusSettings {
+        if config.source == SourceKind::File && config.path.is_none() {
+            problems.add(&["focus"], ConfigError::new("`[focus]` source \"file\" needs a `path`"));
+        }
+
+        let fallback = config.fallback.unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+        if let Err(e) = self.check_profile(&fallback) {
+            problems.add(&["focus", "fallback"], e);
+        }
+        for (index, rule) in rules.iter().enumerate() {
+            if let Err(e) = self.check_profile(&rule.profile) {
+                problems.add(&["rules", &index.to_string(), "profile"], e);
+            }
+        }
+
+        FocusSettings {
+            source: config.source,
+            path: config.path,
+            socket: config.socket,
+            display: config.display,
+            xauthority: config.xauthority,
+            fallback,
+            rules,
+        }
+    }
+
+    /// The `[control]` socket settings.
+    pub fn control(&self) -> &ControlSettings {
+        &self.control
+    }
+
+    /// Whether `[buttons]` or `[wheel]` remap the pointer interface, which
+    /// then has to be grabbed.
+    pub fn grabs_pointer(&self) -> bool {
+        !self.pointer.is_empty()
+    }
+
+    /// Whether any binding or macro presses a `Pad::` button or another
+    /// code only the virtual gamepad has.
+    pub fn uses_gamepad(&self) -> bool {
+        self.chords()
+            .any(|chord| chord.iter().any(|input| input.class() == key_codes::Class::Button))
+    }
+
+    /// Whether the virtual pointer is needed: the pointer interface is
+    /// grabbed, or a binding or macro clicks, scrolls or moves the pointer.
+    pub fn uses_pointer(&self) -> bool {
+        self.grabs_pointer()
+            || self
+                .actions()
+                .any(|action| matches!(action, Action::Scroll(_) | Action::Move(..) | Action::DoubleClick(_)))
+            || self
+                .chords()
+                .any(|chord| chord.iter().any(|input| input.class() == key_codes::Class::Mouse))
+    }
+
+    /// Every action bound in any profile, layer or `[buttons]` table.
+    fn actions(&self) -> impl Iterator<Item = &Action> {
+        self.profiles
+            .iter()
+            .flat_map(|profile| {
+                let layers = profile.layers.values().flat_map(|layer| layer.keys.iter().flatten());
+                profile.keys.iter().chain(layers)
+            })
+            .chain(self.pointer.values())
+    }
+
+    /// Every chord a binding or macro can press.
+    fn chords(&self) -> impl Iterator<Item = &Chord> {
+        let steps = self.macros.values().flat_map(|macro_| macro_.steps.iter());
+        self.actions()
+            .flat_map(Action::chords)
+            .chain(steps.filter_map(MacroStep::chord))
+    }
+
+    /// The `[device]` to remap.
+    pub fn device(&self) -> &DeviceSettings {
+        &self.device
+    }
+
+    /// How the virtual keyboard presents itself, from `[virtual_device]`.
+    pub fn virtual_device(&self) -> &VirtualDeviceSettings {
+        &self.virtual_device
+    }
+
+    /// The `[focus]` settings and `[[rules]]`, if the config has them.
+    pub fn focus(&self) -> Option<&FocusSettings> {
+        self.focus.as_ref()
+    }
+
+    fn read_profile(&self, problems: &mut Problems, name: &str, config: ProfileConfig) -> Profile {
+        let mut profile = Profile::named(name);
+        let prefix = match name {
+            DEFAULT_PROFILE => vec![],
+            name => vec!["profiles", name],
+        };
+
+        for name in config.layers.keys() {
+            profile.layers.insert(name.clone(), Layer::default());
+        }
+
+        let count = self.device.buttons().len();
+        let parse = |name: &str| parse_button(name, count);
+        let path = [&prefix[..], &["keys"]].concat();
+        for (index, action) in self.read_buttons(problems, &profile, &path, config.keys, parse) {
+            profile.keys[index] = action;
+        }
+
+        for (name, layer) in config.layers {
+            let mut keys: [Option<Action>; 12] = Default::default();
+            let path = [&prefix[..], &["layers", &name, "keys"]].concat();
+            for (index, action) in self.read_buttons(problems, &profile, &path, layer.keys, parse) {
+                keys[index] = Some(action);
+            }
+            profile.layers.insert(name, Layer { keys });
+        }
+
+        profile
+    }
+
+    /// Reads a table of button bindings, reporting bad button names,
+    /// buttons bound twice and bad actions. `parse` turns a name into a
+    /// button index.
+    fn read_buttons(
+        &self,
+        problems: &mut Problems,
+        profile: &Profile,
+        path: &[&str],
+        keys: BTreeMap<String, toml::Value>,
+        parse: impl Fn(&str) -> Result<usize, ConfigError>,
+    ) -> Vec<(usize, Action)> {
+        let mut seen: HashMap<usize, String> = HashMap::new();
+        let mut buttons = Vec::new();
+        for (button, value) in keys {
+            let path = [path, &[button.as_str()]].concat();
+            let index = match parse(&button) {
+                Ok(index) => index,
+                Err(e) => {
+                    problems.add(&path, e);
+                    continue;
+                }
+            };
+            let Some(action) = problems.read::<Action>(&path, value) else {
+                continue;
+            };
+            if let Err(e) = self.check_action(profile, &action) {
+                problems.add(&path, e);
+                continue;
+            }
+
+            if let Some(first) = seen.get(&index) {
+                let message = format!("{} is already bound as `{}`", button_name(index), first);
+                problems.add(&path, ConfigError::new(message));
+                continue;
+            }
+            seen.insert(index, button.clone());
+            buttons.push((index, action));
+        }
+        buttons
+    }
+
+    /// Replaces the layout used to type text, e.g. with a custom [`Layout`].
+    pub fn set_layout(&mut self, layout: Arc<dyn Layout>) {
+        self.text.layout = layout;
+    }
+
+    /// The profile whose bindings are in effect.
+    pub fn profile(&self) -> &Profile {
+        &self.profiles[self.active]
+    }
+
+    /// Names of all profiles, in switching order.
+    pub fn profile_names(&self) -> Vec<&str> {
+        self.profiles.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    /// Makes the named profile active.
+    pub fn set_profile(&mut self, name: &str) -> Result<(), String> {
+        self.active = self
+            .profiles
+            .iter()
+            .position(|p| p.name == name)
+            .ok_or_else(|| format!("Unknown profile: {}", name))?;
+        Ok(())
+    }
+
+    /// Changes the active profile and returns whether it actually changed.
+    pub fn switch_profile(&mut self, switch: &ProfileSwitch) -> Result<bool, String> {
+        let previous = self.active;
+        let count = self.profiles.len();
+        match switch {
+            ProfileSwitch::Next => self.active = (self.active + 1) % count,
+            ProfileSwitch::Previous => self.active = (self.active + count - 1) % count,
+            ProfileSwitch::Named(name) => self.set_profile(name)?,
+        }
+        Ok(self.active != previous)
+    }
+
+    /// Checks that any macro, layer or profile an action refers to exists,
+    /// and that any text it types can be typed.
+    fn check_action(&self, profile: &Profile, action: &Action) -> Result<(), ConfigError> {
+        match action {
+            Action::Text(text) => check_text(text, &self.text).map_err(ConfigError::new),
+            Action::Macro(name) if !self.macros.contains_key(name) => {
+                Err(ConfigError::new(format!("Unknown macro: {}", name))
+                    .suggest(name, self.macros.keys()))
+            }
+            Action::Layer(name) if !profile.layers.contains_key(name) => {
+                Err(ConfigError::new(format!("Unknown layer: {}", name))
+                    .suggest(name, profile.layers.keys()))
+            }
+            Action::Profile(ProfileSwitch::Named(name)) => self.check_profile(name),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_profile(&self, name: &str) -> Result<(), ConfigError> {
+        if self.profiles.iter().any(|p| p.name == name) {
+            return Ok(());
+        }
+        Err(ConfigError::new(format!("Unknown profile: {}", name)).suggest(name, self.profile_names()))
+    }
+
+    /// Writes the mapping back out as a config file, with every default
+    /// filled in.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        if let Some(hook) = &self.on_profile_switch {
+            out.push_str(&format!("on_profile_switch = {}\n\n", hook));
+        }
+
+        for profile in &self.profiles {
+            let prefix = match profile.name.as_str() {
+                DEFAULT_PROFILE => String::new(),
+                name => format!("profiles.{}.", quote_key(name)),
+            };
+            out.push_str(&format!("[{}keys]\n", prefix));
+            for (index, action) in profile.keys.iter().enumerate() {
+                out.push_str(&format!("\"{}\" = {}\n", index + 1, action.to_toml()));
+            }
+            for (name, layer) in sorted(&profile.layers) {
+                out.push_str(&format!("\n[{}layers.{}.keys]\n", prefix, quote_key(name)));
+                for (index, action) in layer.keys.iter().enumerate() {
+                    if let Some(action) = action {
+                        out.push_str(&format!("\"{}\" = {}\n", index + 1, action.to_toml()));
+                    }
+                }
+            }
+            out.push('\n');
+        }
+
+        for (name, macro_) in sorted(&self.macros) {
+            let steps: Vec<String> = macro_.steps.iter().map(MacroStep::to_toml).collect();
+            out.push_str(&format!("[macros.{}]\nsteps = [{}]\n\n", quote_key(name), steps.join(", ")));
+        }
+
+        let unicode = match self.text.unicode {
+            UnicodeEntry::CtrlShiftU => "ctrl-shift-u",
+            UnicodeEntry::None => "none",
+        };
+        out.push_str(&format!(
+            "[text]\nlayout = {}\nunicode = {}\n",
+            quote(self.text.layout.name()),
+            quote(unicode)
+        ));
+
+        if let Some(focus) = &self.focus {
+            out.push_str(&format!("\n[focus]\nsource = {}\n", quote(focus.source.name())));
+            let optional = [
+                ("path", &focus.path),
+                ("socket", &focus.socket),
+                ("display", &focus.display),
+                ("xauthority", &focus.xauthority),
+            ];
+            for (key, value) in optional {
+                if let Some(value) = value {
+                    out.push_str(&format!("{} = {}\n", key, quote(value)));
+                }
+            }
+            out.push_str(&format!("fallback = {}\n", quote(&focus.fallback)));
+            for rule in &focus.rules {
+                out.push_str(&format!(
+                    "\n[[rules]]\nclass = {}\nprofile = {}\n",
+                    quote(&rule.class),
+                    quote(&rule.profile)
+                ));
+            }
+        }
+
+        let pointer_tables = [
+            ("buttons", FIRST_MOUSE_BUTTON, MOUSE_BUTTONS.iter().map(|(name, _)| *name).collect::<Vec<_>>()),
+            ("wheel", FIRST_WHEEL_DIRECTION, WHEEL_DIRECTIONS.to_vec()),
+        ];
+        for (table, first, names) in pointer_tables {
+            let bound: Vec<String> = names
+                .iter()
+                .enumerate()
+                .filter_map(|(offset, name)| {
+                    let action = self.pointer.get(&(first + offset))?;
+                    Some(format!("{} = {}\n", name, action.to_toml()))
+                })
+                .collect();
+            if !bound.is_empty() {
+                out.push_str(&format!("\n[{}]\n{}", table, bound.concat()));
+            }
+        }
+
+        out.push_str(&device_to_toml(&self.device));
+
+        let virtual_device = &self.virtual_device;
+        out.push_str(&format!(
+            "\n[virtual_device]\nname = {}\nvendor = \"{:04x}\"\nproduct = \"{:04x}\"\nversion = \"{:04x}\"\n",
+            quote(&virtual_device.name),
+            virtual_device.vendor,
+            virtual_device.product,
+            virtual_device.version
+        ));
+        match input_device::BUSES.iter().find(|(_, bus)| *bus == virtual_device.bus) {
+            Some((name, _)) => out.push_str(&format!("bus = {}\n", quote(name))),
+            None => out.push_str(&format!("bus = {}\n", virtual_device.bus)),
+        }
+
+        out.push_str(&format!(
+            "\n[control]\nenabled = {}\nsocket = {}\n",
+            self.control.enabled,
+            quote(&self.control.socket)
+        ));
+        if let Some(group) = &self.control.group {
+            out.push_str(&format!("group = {}\n", quote(group)));
+        }
+        out
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn debug_mappings(&self) -> String {
+        let mut result = String::new();
+        let mut pointer: Vec<_> = self.pointer.iter().collect();
+        pointer.sort_by_key(|(index, _)| **index);
+        for (index, action) in pointer {
+            result.push_str(&format!("{} -> {}\n", button_name(*index), action.debug_name()));
+        }
+        for (index, profile) in self.profiles.iter().enumerate() {
+            let active = if index == self.active { " (active)" } else { "" };
+            result.push_str(&format!("Profile {}{}:\n", profile.name, active));
+            for (idx, key) in profile.keys.iter().enumerate() {
+                result.push_str(&format!("  Button {} (index {}) -> {}\n",
+                    idx + 1, idx, key.debug_name()));
+            }
+            for (name, layer) in &profile.layers {
+                result.push_str(&format!("  Layer {}:\n", name));
+                for (idx, key) in layer.keys.iter().enumerate() {
+                    if let Some(key) = key {
+                        result.push_str(&format!("    Button {} (index {}) -> {}\n",
+                            idx + 1, idx, key.debug_name()));
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Name of the profile built from the top-level `[keys]` and `[layers]`.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// A complete set of button bindings that can be switched to at runtime.
+#[derive(Clone)]
+pub struct Profile {
+    pub(crate) name: String,
+    pub(crate) keys: [Action; 12],
+    pub(crate) layers: HashMap<String, Layer>,
+}
+impl Default for Profile {
+    fn default() -> Self {
+        Self::named(DEFAULT_PROFILE)
+    }
+}
+impl Profile {
+    fn named(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            keys: [
+                Key::_1.into(),
+                Key::_2.into(),
+                Key::_3.into(),
+                Key::_4.into(),
+                Key::_5.into(),
+                Key::_6.into(),
+                Key::_7.into(),
+                Key::_8.into(),
+                Key::_9.into(),
+                Key::_0.into(),
+                Key::Minus.into(),
+                Key::Equal.into(),
+            ],
+            layers: HashMap::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Where the control socket is created unless `[control]` says otherwise.
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/config-2014-naga.sock";
+
+/// Settings for the control socket.
+#[derive(Clone)]
+pub struct ControlSettings {
+    pub(crate) enabled: bool,
+    pub(crate) socket: String,
+    // Group allowed to use the socket besides root
+    pub(crate) group: Option<String>,
+}
+impl Default for ControlSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            socket: DEFAULT_CONTROL_SOCKET.to_string(),
+            group: None,
+        }
+    }
+}
+impl ControlSettings {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+}
+
+/// Where to find out which application has focus.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// `_NET_ACTIVE_WINDOW` on an X11 display.
+    X11,
+    /// The sway IPC socket.
+    Sway,
+    /// The i3 IPC socket.
+    I3,
+    /// Lines written to a file or FIFO.
+    File,
+}
+
+impl SourceKind {
+    /// The name used for the source in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SourceKind::X11 => "x11",
+            SourceKind::Sway => "sway",
+            SourceKind::I3 => "i3",
+            SourceKind::File => "file",
+        }
+    }
+}
+
+/// Settings for switching profiles with the focused application.
+#[derive(Clone)]
+pub struct FocusSettings {
+    pub(crate) source: SourceKind,
+    // File or FIFO for the file source
+    pub(crate) path: Option<String>,
+    // IPC socket for sway and i3, found automatically if not set
+    pub(crate) socket: Option<String>,
+    pub(crate) display: Option<String>,
+    pub(crate) xauthority: Option<String>,
+    // Profile used when no rule matches
+    pub(crate) fallback: String,
+    pub(crate) rules: Vec<Rule>,
+}
+impl FocusSettings {
+    /// The profile of the first rule matching `app`, ignoring case, or the
+    /// fallback profile.
+    pub fn profile_for(&self, app: &str) -> &str {
+        self.rules
+            .iter()
+            .find(|rule| rule.class.eq_ignore_ascii_case(app))
+            .map_or(&self.fallback, |rule| &rule.profile)
+    }
+}
+
+/// Switches to `profile` while an application with this app id or window
+/// class has focus.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub(crate) class: String,
+    pub(crate) profile: String,
+}
+
+/// Which profile a profile switch goes to.
+#[derive(Clone, PartialEq, Debug)]
+pub enum ProfileSwitch {
+    /// The next profile, wrapping around after the last.
+    Next,
+    /// The previous profile, wrapping around before the first.
+    Previous,
+    /// A profile by name.
+    Named(String),
+}
+impl ProfileSwitch {
+    /// Parses `"next"`, `"previous"` or a profile name.
+    pub fn from_name(name: &str) -> ProfileSwitch {
+        match name {
+            "next" => ProfileSwitch::Next,
+            "previous" => ProfileSwitch::Previous,
+            _ => ProfileSwitch::Named(name.to_string()),
+        }
+    }
+}
+
+/// A problem found in a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    /// Dotted path of the entry at fault, such as `profiles.wow.keys.3`.
+    pub key: Option<String>,
+    /// 1-based line of the entry, if it could be found.
+    pub line: Option<usize>,
+    /// 1-based column of the entry, if it could be found.
+    pub column: Option<usize>,
+    pub message: String,
+    /// The known name closest to a misspelt one.
+    pub suggestion: Option<String>,
+}
+impl ConfigError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            key: None,
+            line: None,
+            column: None,
+            message: message.into(),
+            suggestion: None,
+        }
+    }
+
+    /// Suggests the candidate closest to `name`, if any is close enough to
+    /// be a likely typo.
+    fn suggest<S: AsRef<str>>(mut self, name: &str, candidates: impl IntoIterator<Item = S>) -> Self {
+        let name = name.to_lowercase();
+        let max_distance = (name.chars().count() / 3).max(1);
+        self.suggestion = candidates
+            .into_iter()
+            .map(|candidate| {
+                let distance = edit_distance(&name, &candidate.as_ref().to_lowercase());
+                (distance, candidate.as_ref().to_string())
+            })
+            .filter(|(distance, _)| *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate);
+        self
+    }
+}
+/// Writes `line:column: key: message (did you mean `suggestion`?)`,
+/// leaving out whatever is unknown.
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, "{}: ", key)?;
+        }
+        f.write_str(&self.message)?;
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, " (did you mean `{}`?)", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every problem found in a config file.
+#[derive(Clone, Debug)]
+pub struct ConfigErrors {
+    pub file: Option<String>,
+    pub errors: Vec<ConfigError>,
+}
+/// Writes one problem per line, each prefixed with the file name.
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, error) in self.errors.iter().enumerate() {
+            if index > 0 {
+                f.write_str("\n")?;
+            }
+            match (&self.file, error.line) {
+                (Some(file), Some(_)) => write!(f, "{}:", file)?,
+                (Some(file), None) => write!(f, "{}: ", file)?,
+                (None, _) => {}
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ConfigErrors {}
+
+/// Collects the problems found while reading a config, with their
+/// locations in the source.
+struct Problems<'a> {
+    source: &'a str,
+    errors: Vec<ConfigError>,
+}
+impl Problems<'_> {
+    fn add(&mut self, path: &[&str], mut error: ConfigError) {
+        error.key = Some(path.join("."));
+        if let Some((line, column)) = locate(self.source, path) {
+            error.line = Some(line);
+            error.column = Some(column);
+        }
+        self.errors.push(error);
+    }
+
+    /// Deserializes `value`, reporting it at `path` if it is invalid.
+    fn read<T: DeserializeOwned>(&mut self, path: &[&str], value: toml::Value) -> Option<T> {
+        let unknown = unknown_key_name(&value);
+        match value.try_into() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let mut error = ConfigError::new(e.to_string());
+                if let Some(name) = unknown {
+                    let words = BINDING_WORDS.iter().map(|word| word.to_string());
+                    error = error.suggest(&name, key_names().into_iter().chain(words));
+                }
+                self.add(path, error);
+                None
+            }
+        }
+    }
+
+    /// Deserializes every entry of a table on its own, skipping bad ones.
+    fn read_each<T: DeserializeOwned>(&mut self, path: &[&str], value: toml::Value) -> BTreeMap<String, T> {
+        let toml::Value::Table(table) = value else {
+            self.add(path, ConfigError::new(format!("expected a table, found {}", value.type_str())));
+            return BTreeMap::new();
+        };
+        table
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let value = self.read(&[path, &[name.as_str()]].concat(), value)?;
+                Some((name, value))
+            })
+            .collect()
+    }
+}
+
+/// Bindings written as a single word rather than as keys.
+const BINDING_WORDS: &[&str] = &["Passthrough", "Disabled"];
+
+/// Every key name a chord can use.
+fn key_names() -> Vec<String> {
+    let mut names = InputKey::names();
+    names.extend(InputKeyPad::names());
+    names.extend(InputCode::friendly_names());
+    names.extend(InputMouse::names());
+    names.extend(InputPad::names());
+    names.extend(key_codes::KEY_CODES.iter().map(|(name, _)| name.to_string()));
+    names
+}
+
+/// Finds the first key name in a binding or macro that isn't a known key.
+fn unknown_key_name(value: &toml::Value) -> Option<String> {
+    // Fields of action tables and macro steps that hold chords
+    const CHORD_FIELDS: &[&str] = &["tap", "hold", "taps", "toggle", "turbo", "press", "release", "steps"];
+
+    match value {
+        toml::Value::String(chord) => {
+            let prefixes = ["Macro::", "Layer::", "Profile::", "text:", "Scroll::", "Move(", "DoubleClick::"];
+            if BINDING_WORDS.contains(&chord.as_str()) || prefixes.iter().any(|prefix| chord.starts_with(prefix)) {
+                return None;
+            }
+            chord
+                .split('+')
+                .map(str::trim)
+                .find(|name| Input::from_name(name).is_none())
+                .map(str::to_string)
+        }
+        toml::Value::Array(items) => items.iter().find_map(unknown_key_name),
+        toml::Value::Table(table) => CHORD_FIELDS
+            .iter()
+            .filter_map(|field| table.get(*field))
+            .find_map(unknown_key_name),
+        _ => None,
+    }
+}
+
+/// Number of single character edits, counting swapping two neighbours as
+/// one, that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in rows[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution.min(rows[i - 1][j] + 1).min(rows[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Finds the 1-based line and column where the entry at `path` is
+/// written, or failing that the closest enclosing table or inline table.
+///
+/// This only understands the TOML that configs are usually written in;
+/// multi-line strings containing `=` or `[` can confuse it.
+fn locate(source: &str, path: &[&str]) -> Option<(usize, usize)> {
+    let mut table: Vec<String> = Vec::new();
+    // Arrays of tables such as `[[rules]]` are numbered from 0
+    let mut array_lengths: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut best: Option<(usize, usize, usize)> = None;
+
+    for (number, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let column = line.chars().count() - trimmed.chars().count() + 1;
+        let entry = if let Some(header) = trimmed.strip_prefix("[[") {
+            let mut name = split_key(header.split("]]").next().unwrap_or_default());
+            let length = array_lengths.entry(name.clone()).or_insert(0);
+            name.push(length.to_string());
+            *length += 1;
+            table = name.clone();
+            name
+        } else if let Some(header) = trimmed.strip_prefix('[') {
+            table = split_key(header.split(']').next().unwrap_or_default());
+            table.clone()
+        } else if let Some(key) = key_of(trimmed) {
+            let mut entry = table.clone();
+            entry.extend(split_key(key));
+            entry
+        } else {
+            continue;
+        };
+
+        let matched = entry.iter().zip(path).take_while(|(a, b)| a == *b).count();
+        if matched < entry.len() {
+            continue;
+        }
+        if matched == path.len() {
+            return Some((number + 1, column));
+        }
+        if best.is_none_or(|(depth, _, _)| matched > depth) {
+            best = Some((matched, number + 1, column));
+        }
+    }
+    best.filter(|(depth, _, _)| *depth > 0)
+        .map(|(_, line, column)| (line, column))
+}
+
+/// The key of a `key = value` line, if the line is one.
+fn key_of(line: &str) -> Option<&str> {
+    if line.starts_with('#') {
+        return None;
+    }
+    let mut quote = None;
+    for (index, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, '=') => return Some(&line[..index]),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits a dotted TOML key into its parts, removing any quotes.
+fn split_key(key: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quote = None;
+    for c in key.trim().chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            (None, '.') => parts.push(String::new()),
+            (None, c) if c.is_whitespace() => {}
+            (_, c) => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Writes `s` as a TOML basic string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Writes `key` as a TOML key, quoting it only if it needs to be.
+fn quote_key(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        quote(key)
+    }
+}
+
+/// The entries of a map in name order.
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+/// Converts a 1-based button name from the config into an index, for a
+/// device with `count` buttons.
+fn parse_button(from_key: &str, count: usize) -> Result<usize, ConfigError> {
+    match from_key.parse::<usize>() {
+        Ok(number) if (1..=count).contains(&number) => Ok(number - 1),
+        Ok(number) => Err(ConfigError::new(format!(
+            "There is no button {}; the device's buttons are numbered 1 to {}",
+            number, count
+        ))),
+        Err(_) => Err(ConfigError::new(format!(
+            "Buttons are numbered 1 to {}, not `{}`",
+            count, from_key
+        ))),
+    }
+}
+
+/// Mouse buttons that `[buttons]` can rebind, with their `BTN_*` codes.
+pub const MOUSE_BUTTONS: &[(&str, u16)] = &[
+    ("Left", 0x110),
+    ("Right", 0x111),
+    ("Middle", 0x112),
+    ("Side", 0x113),
+    ("Extra", 0x114),
+    ("Forward", 0x115),
+    ("Back", 0x116),
+    ("Task", 0x117),
+];
+
+/// Buttons of the virtual gamepad, with the `BTN_*` codes they are known
+/// by. The d-pad is sent as a hat rather than as its `BTN_DPAD_*` codes.
+pub const PAD_BUTTONS: &[(&str, u16)] = &[
+    ("A", 0x130),
+    ("B", 0x131),
+    ("X", 0x133),
+    ("Y", 0x134),
+    ("LB", 0x136),
+    ("RB", 0x137),
+    ("LT", 0x138),
+    ("RT", 0x139),
+    ("Select", 0x13a),
+    ("Start", 0x13b),
+    ("Mode", 0x13c),
+    ("LeftStick", 0x13d),
+    ("RightStick", 0x13e),
+    ("DpadUp", 0x220),
+    ("DpadDown", 0x221),
+    ("DpadLeft", 0x222),
+    ("DpadRight", 0x223),
+];
+
+/// Wheel directions that `[wheel]` can rebind; left and right are the
+/// wheel's tilt.
+pub const WHEEL_DIRECTIONS: &[&str] = &["up", "down", "left", "right"];
+
+// Mouse buttons and wheel directions are numbered on after the side
+// buttons, so the event mapper can treat every binding alike
+pub(crate) const FIRST_MOUSE_BUTTON: usize = device::MAX_BUTTONS;
+pub(crate) const FIRST_WHEEL_DIRECTION: usize = FIRST_MOUSE_BUTTON + MOUSE_BUTTONS.len();
+
+/// The button index of the mouse button that sends `BTN_*` `code`.
+pub(crate) fn mouse_button_index(code: u16) -> Option<usize> {
+    MOUSE_BUTTONS
+        .iter()
+        .position(|(_, button)| *button == code)
+        .map(|offset| FIRST_MOUSE_BUTTON + offset)
+}
+
+/// How a button index is written in configs and messages.
+pub(crate) fn button_name(index: usize) -> String {
+    if index < FIRST_MOUSE_BUTTON {
+        format!("Button {}", index + 1)
+    } else if let Some((name, _)) = MOUSE_BUTTONS.get(index - FIRST_MOUSE_BUTTON) {
+        format!("Mouse button {}", name)
+    } else {
+        let direction = WHEEL_DIRECTIONS.get(index - FIRST_WHEEL_DIRECTION).unwrap_or(&"?");
+        format!("Wheel {}", direction)
+    }
+}
+
+fn parse_mouse_button(name: &str) -> Result<usize, ConfigError> {
+    let names = MOUSE_BUTTONS.iter().map(|(name, _)| *name);
+    match names.clone().position(|button| button == name) {
+        Some(offset) => Ok(FIRST_MOUSE_BUTTON + offset),
+        None => Err(ConfigError::new(format!("Unknown mouse button: {}", name)).suggest(name, names)),
+    }
+}
+
+fn parse_wheel_direction(name: &str) -> Result<usize, ConfigError> {
+    match WHEEL_DIRECTIONS.iter().position(|direction| *direction == name) {
+        Some(offset) => Ok(FIRST_WHEEL_DIRECTION + offset),
+        None => Err(ConfigError::new(format!("Unknown wheel direction: {}", name))
+            .suggest(name, WHEEL_DIRECTIONS)),
+    }
+}
+
+/// Names of the tables and values allowed at the top of a config.
+const TOP_LEVEL_KEYS: &[&str] = &[
+    "keys",
+    "layers",
+    "profiles",
+    "macros",
+    "text",
+    "on_profile_switch",
+    "focus",
+    "rules",
+    "control",
+    "device",
+    "buttons",
+    "wheel",
+    "virtual_device",
+];
+
+/// The top level of a config file. Each table is read on its own, so a
+/// mistake in one doesn't hide mistakes in the others.
+#[derive(Default)]
+struct Config {
+    keys: BTreeMap<String, toml::Value>,
+    layers: BTreeMap<String, LayerConfig>,
+    profiles: BTreeMap<String, ProfileConfig>,
+    macros: BTreeMap<String, Macro>,
+    text: Option<TextConfig>,
+    on_profile_switch: Option<Action>,
+    focus: Option<FocusConfig>,
+    rules: Vec<Rule>,
+    control: Option<ControlConfig>,
+    device: Option<DeviceConfig>,
+    buttons: BTreeMap<String, toml::Value>,
+    wheel: BTreeMap<String, toml::Value>,
+    virtual_device: Option<VirtualDeviceConfig>,
+}
+impl Config {
+    fn read(problems: &mut Problems, table: toml::value::Table) -> Config {
+        let mut config = Config::default();
+        for (name, value) in table {
+            let path = [name.as_str()];
+            match name.as_str() {
+                "keys" => config.keys = problems.read(&path, value).unwrap_or_default(),
+                "layers" => config.layers = problems.read_each(&path, value),
+                "profiles" => config.profiles = problems.read_each(&path, value),
+                "macros" => config.macros = problems.read_each(&path, value),
+                "text" => config.text = problems.read(&path, value),
+                "on_profile_switch" => config.on_profile_switch = problems.read(&path, value),
+                "focus" => config.focus = problems.read(&path, value),
+                "rules" => config.rules = problems.read(&path, value).unwrap_or_default(),
+                "control" => config.control = problems.read(&path, value),
+                "device" => config.device = problems.read(&path, value),
+                "buttons" => config.buttons = problems.read(&path, value).unwrap_or_default(),
+                "wheel" => config.wheel = problems.read(&path, value).unwrap_or_default(),
+                "virtual_device" => config.virtual_device = problems.read(&path, value),
+                _ => problems.add(
+                    &path,
+                    ConfigError::new(format!("Unknown table: {}", name)).suggest(&name, TOP_LEVEL_KEYS),
+                ),
+            }
+        }
+        config
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControlConfig {
+    enabled: Option<bool>,
+    socket: Option<String>,
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceConfig {
+    preset: Option<String>,
+    vendor: Option<UsbId>,
+    product: Option<UsbIds>,
+    name: Option<String>,
+    phys: Option<String>,
+    path: Option<String>,
+    pointer_phys: Option<String>,
+    pointer_path: Option<String>,
+    buttons: Option<Vec<ButtonCode>>,
+    passthrough: Option<bool>,
+}
+impl DeviceConfig {
+    fn has_criteria(&self) -> bool {
+        self.vendor.is_some()
+            || self.product.is_some()
+            || self.name.is_some()
+            || self.phys.is_some()
+            || self.path.is_some()
+    }
+}
+
+/// Builds the `[device]` settings. Criteria given with a preset replace
+/// the preset's; without one they describe a device of their own.
+fn read_device(problems: &mut Problems, config: DeviceConfig) -> DeviceSettings {
+    let mut settings = match &config.preset {
+        Some(name) => match device::preset(name) {
+            Some(preset) => preset.settings(),
+            None => {
+                let names = device::PRESETS.iter().map(|preset| preset.name);
+                problems.add(
+                    &["device", "preset"],
+                    ConfigError::new(format!("Unknown device preset: {}", name)).suggest(name, names),
+                );
+                return DeviceSettings::default();
+            }
+        },
+        None if config.has_criteria() => DeviceSettings::custom(),
+        None => DeviceSettings::default(),
+    };
+
+    if let Some(UsbId(vendor)) = config.vendor {
+        settings.matcher.vendor = Some(vendor);
+    }
+    if let Some(UsbIds(products)) = config.product {
+        settings.matcher.products = products;
+    }
+    if let Some(name) = config.name {
+        match Regex::new(&name) {
+            Ok(pattern) => settings.matcher.name = Some(pattern),
+            Err(e) => problems.add(&["device", "name"], ConfigError::new(e.to_string())),
+        }
+    }
+    if let Some(phys) = config.phys {
+        settings.matcher.phys = Some(phys);
+    }
+    if let Some(path) = config.path {
+        settings.matcher.path = Some(path.into());
+    }
+    if let Some(phys) = config.pointer_phys {
+        settings.pointer_phys = Some(phys);
+    }
+    if let Some(path) = config.pointer_path {
+        settings.pointer_path = Some(path.into());
+    }
+
+    if let Some(passthrough) = config.passthrough {
+        settings.passthrough = passthrough;
+    }
+
+    if let Some(buttons) = config.buttons {
+        let codes: Vec<u16> = buttons.into_iter().map(|ButtonCode(code)| code).collect();
+        if codes.is_empty() || codes.len() > device::MAX_BUTTONS {
+            let message = format!("`buttons` needs 1 to {} key codes", device::MAX_BUTTONS);
+            problems.add(&["device", "buttons"], ConfigError::new(message));
+        } else if let Some(code) = codes.iter().find(|&code| codes.iter().filter(|&c| c == code).count() > 1) {
+            let message = format!("Key code {} is listed twice in `buttons`", code);
+            problems.add(&["device", "buttons"], ConfigError::new(message));
+        } else {
+            settings.buttons = codes;
+        }
+    }
+    settings
+}
+
+fn device_to_toml(settings: &DeviceSettings) -> String {
+    let mut out = String::from("\n[device]\n");
+    if let Some(preset) = settings.preset() {
+        out.push_str(&format!("preset = {}\n", quote(preset)));
+    }
+    let matcher = settings.matcher();
+    if let Some(vendor) = matcher.vendor {
+        out.push_str(&format!("vendor = \"{:04x}\"\n", vendor));
+    }
+    match &matcher.products[..] {
+        [] => {}
+        [product] => out.push_str(&format!("product = \"{:04x}\"\n", product)),
+        products => {
+            let ids: Vec<String> = products.iter().map(|id| format!("\"{:04x}\"", id)).collect();
+            out.push_str(&format!("product = [{}]\n", ids.join(", ")));
+        }
+    }
+    if let Some(name) = &matcher.name {
+        out.push_str(&format!("name = {}\n", quote(name.as_str())));
+    }
+    if let Some(phys) = &matcher.phys {
+        out.push_str(&format!("phys = {}\n", quote(phys)));
+    }
+    if let Some(path) = &matcher.path {
+        out.push_str(&format!("path = {}\n", quote(&path.to_string_lossy())));
+    }
+    if let Some(phys) = &settings.pointer_phys {
+        out.push_str(&format!("pointer_phys = {}\n", quote(phys)));
+    }
+    if let Some(path) = &settings.pointer_path {
+        out.push_str(&format!("pointer_path = {}\n", quote(&path.to_string_lossy())));
+    }
+    // Arrays can't mix names and numbers, so names are only used if every
+    // code has one
+    let names: Option<Vec<String>> = settings
+        .buttons()
+        .iter()
+        .map(|&code| key_codes::name_of(code).map(quote))
+        .collect();
+    let buttons = names.unwrap_or_else(|| settings.buttons().iter().map(u16::to_string).collect());
+    out.push_str(&format!("buttons = [{}]\n", buttons.join(", ")));
+    out.push_str(&format!("passthrough = {}\n", settings.passthrough()));
+    out
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VirtualDeviceConfig {
+    name: Option<String>,
+    vendor: Option<UsbId>,
+    product: Option<UsbId>,
+    version: Option<UsbId>,
+    bus: Option<Bus>,
+}
+
+/// Builds the `[virtual_device]` settings; anything left out keeps its
+/// default.
+fn read_virtual_device(problems: &mut Problems, config: VirtualDeviceConfig) -> VirtualDeviceSettings {
+    let mut settings = VirtualDeviceSettings::default();
+    if let Some(name) = config.name {
+        if name.is_empty() || name.len() > input_device::MAX_NAME_LEN {
+            let message = format!("`name` needs 1 to {} bytes", input_device::MAX_NAME_LEN);
+            problems.add(&["virtual_device", "name"], ConfigError::new(message));
+        } else if name.contains('\0') {
+            problems.add(&["virtual_device", "name"], ConfigError::new("`name` can't contain NUL"));
+        } else {
+            settings.name = name;
+        }
+    }
+    if let Some(UsbId(vendor)) = config.vendor {
+        settings.vendor = vendor;
+    }
+    if let Some(UsbId(product)) = config.product {
+        settings.product = product;
+    }
+    if let Some(UsbId(version)) = config.version {
+        settings.version = version;
+    }
+    if let Some(Bus(bus)) = config.bus {
+        settings.bus = bus;
+    }
+    settings
+}
+
+/// A bus type, by name such as `"usb"` or as a kernel `BUS_*` number.
+struct Bus(u16);
+impl<'de> Deserialize<'de> for Bus {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BusVisitor;
+        impl Visitor<'_> for BusVisitor {
+            type Value = Bus;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a bus type such as \"usb\"")
+            }
+
+            fn visit_i64<E: Error>(self, bus: i64) -> Result<Bus, E> {
+                u16::try_from(bus)
+                    .map(Bus)
+                    .map_err(|_| E::custom(format!("Bus type out of range: {}", bus)))
+            }
+
+            fn visit_str<E: Error>(self, name: &str) -> Result<Bus, E> {
+                let names = input_device::BUSES.iter().map(|(name, _)| *name);
+                match input_device::BUSES.iter().find(|(bus, _)| *bus == name) {
+                    Some((_, bus)) => Ok(Bus(*bus)),
+                    None => Err(E::custom(
+                        ConfigError::new(format!("Unknown bus type: {}", name)).suggest(name, names),
+                    )),
+                }
+            }
+        }
+        deserializer.deserialize_any(BusVisitor)
+    }
+}
+
+/// A USB vendor or product id, written in hex like `lsusb` shows it
+/// (`"1532"` or `"0x1532"`), or as a plain number.
+struct UsbId(u16);
+impl<'de> Deserialize<'de> for UsbId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(UsbIdVisitor)
+    }
+}
+
+struct UsbIdVisitor;
+impl Visitor<'_> for UsbIdVisitor {
+    type Value = UsbId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a USB id such as \"1532\"")
+    }
+
+    fn visit_i64<E: Error>(self, id: i64) -> Result<UsbId, E> {
+        u16::try_from(id)
+            .map(UsbId)
+            .map_err(|_| E::custom(format!("USB id out of range: {}", id)))
+    }
+
+    fn visit_str<E: Error>(self, id: &str) -> Result<UsbId, E> {
+        let digits = id.strip_prefix("0x").unwrap_or(id);
+        u16::from_str_radix(digits, 16)
+            .map(UsbId)
+            .map_err(|_| E::custom(format!("Not a hex USB id: {}", id)))
+    }
+}
+
+/// One USB product id, or a list of them for a mouse sold under several.
+struct UsbIds(Vec<u16>);
+impl<'de> Deserialize<'de> for UsbIds {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct UsbIdsVisitor;
+        impl<'de> Visitor<'de> for UsbIdsVisitor {
+            type Value = UsbIds;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a USB id such as \"0040\", or a list of them")
+            }
+
+            fn visit_i64<E: Error>(self, id: i64) -> Result<UsbIds, E> {
+                UsbIdVisitor.visit_i64(id).map(|UsbId(id)| UsbIds(vec![id]))
+            }
+
+            fn visit_str<E: Error>(self, id: &str) -> Result<UsbIds, E> {
+                UsbIdVisitor.visit_str(id).map(|UsbId(id)| UsbIds(vec![id]))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<UsbIds, A::Error> {
+                let mut ids = Vec::new();
+                while let Some(UsbId(id)) = seq.next_element()? {
+                    ids.push(id);
+                }
+                Ok(UsbIds(ids))
+            }
+        }
+        deserializer.deserialize_any(UsbIdsVisitor)
+    }
+}
+
+/// A key code in `[device] buttons`, by number or kernel name such as
+/// `KEY_1`.
+struct ButtonCode(u16);
+impl<'de> Deserialize<'de> for ButtonCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ButtonCodeVisitor;
+        impl Visitor<'_> for ButtonCodeVisitor {
+            type Value = ButtonCode;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a key code or a name such as \"KEY_1\"")
+            }
+
+            fn visit_i64<E: Error>(self, code: i64) -> Result<ButtonCode, E> {
+                match u16::try_from(code) {
+                    Ok(code) if code <= key_codes::KEY_MAX => Ok(ButtonCode(code)),
+                    _ => Err(E::custom(format!("Key code out of range: {}", code))),
+                }
+            }
+
+            fn visit_str<E: Error>(self, name: &str) -> Result<ButtonCode, E> {
+                key_codes::by_name(name)
+                    .map(ButtonCode)
+                    .ok_or_else(|| E::custom(format!("Unknown key code name: {}", name)))
+            }
+        }
+        deserializer.deserialize_any(ButtonCodeVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FocusConfig {
+    source: SourceKind,
+    path: Option<String>,
+    socket: Option<String>,
+    display: Option<String>,
+    xauthority: Option<String>,
+    fallback: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileConfig {
+    #[serde(default)]
+    keys: BTreeMap<String, toml::Value>,
+    #[serde(default)]
+    layers: BTreeMap<String, LayerConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TextConfig {
+    layout: Option<String>,
+    unicode: Option<UnicodeEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConfig {
+    keys: BTreeMap<String, toml::Value>,
+}
+
+/// An alternate set of bindings active while its layer key is held.
+///
+/// Buttons left unbound in a layer fall through to the layer below.
+#[derive(Clone, Default)]
+pub struct Layer {
+    pub(crate) keys: [Option<Action>; 12],
+}
+
+/// What a side button does when pressed.
+#[derive(Clone)]
+pub enum Action {
+    /// Hold the keys down for as long as the button is held.
+    Keys(Chord),
+    /// Play the named macro from `[macros.<name>]` on a worker thread.
+    Macro(String),
+    /// Activate the named layer from `[layers.<name>]` while held.
+    Layer(String),
+    /// Type the text, written as `"text:<text>"`.
+    Text(String),
+    /// Send one chord when tapped and another when held.
+    TapHold(TapHold),
+    /// Send a different chord depending on how many times the button is tapped.
+    TapDance(TapDance),
+    /// Press the chord on one press and release it on the next.
+    Toggle(Chord),
+    /// Repeatedly tap the chord while the button is held.
+    Turbo(Turbo),
+    /// Run a command on press without sending any key.
+    Exec(Exec),
+    /// Switch profiles, written as `"Profile::<name>"`, `"Profile::next"`
+    /// or `"Profile::previous"`.
+    Profile(ProfileSwitch),
+    /// Turn the wheel on press, written as `"Scroll::Up(3)"`.
+    Scroll(Scroll),
+    /// Move the pointer on press, written as `"Move(10,0)"`.
+    Move(i32, i32),
+    /// Click a mouse button twice on press, written as
+    /// `"DoubleClick::Left"`.
+    DoubleClick(InputMouse),
+    /// Send the button's own key code, as if it weren't remapped.
+    Passthrough,
+    /// Do nothing, swallowing the button.
+    Disabled,
+}
+impl Action {
+    #[cfg(debug_assertions)]
+    pub fn debug_name(&self) -> String {
+        self.to_string()
+    }
+
+    /// The chords the action can press.
+    fn chords(&self) -> Vec<&Chord> {
+        match self {
+            Action::Keys(chord) | Action::Toggle(chord) => vec![chord],
+            Action::TapHold(tap_hold) => vec![&tap_hold.tap, &tap_hold.hold],
+            Action::TapDance(tap_dance) => tap_dance.taps.iter().collect(),
+            Action::Turbo(turbo) => vec![&turbo.chord],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Writes the action as a TOML value.
+    fn to_toml(&self) -> String {
+        match self {
+            Action::Keys(_)
+            | Action::Macro(_)
+            | Action::Layer(_)
+            | Action::Text(_)
+            | Action::Profile(_)
+            | Action::Scroll(_)
+            | Action::Move(..)
+            | Action::DoubleClick(_)
+            | Action::Passthrough
+            | Action::Disabled => quote(&self.to_string()),
+            _ => self.to_string(),
+        }
+    }
+}
+/// Writes the action the way it is written in the config, without the
+/// quotes around string forms.
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Keys(chord) => write!(f, "{}", chord),
+            Action::Macro(name) => write!(f, "Macro::{}", name),
+            Action::Layer(name) => write!(f, "Layer::{}", name),
+            Action::Text(text) => write!(f, "text:{}", text),
+            Action::TapHold(tap_hold) => write!(
+                f,
+                "{{ tap = \"{}\", hold = \"{}\", hold_ms = {} }}",
+                tap_hold.tap, tap_hold.hold, tap_hold.hold_ms
+            ),
+            Action::TapDance(tap_dance) => write!(
+                f,
+                "{{ taps = [{}], window_ms = {} }}",
+                tap_dance
+                    .taps
+                    .iter()
+                    .map(|chord| format!("\"{}\"", chord))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                tap_dance.window_ms
+            ),
+            Action::Toggle(chord) => write!(f, "{{ toggle = \"{}\" }}", chord),
+            Action::Turbo(turbo) => write!(
+                f,
+                "{{ turbo = \"{}\", rate_hz = {} }}",
+                turbo.chord, turbo.rate_hz
+            ),
+            Action::Exec(exec) => write!(f, "{}", exec),
+            Action::Profile(ProfileSwitch::Next) => f.write_str("Profile::next"),
+            Action::Profile(ProfileSwitch::Previous) => f.write_str("Profile::previous"),
+            Action::Profile(ProfileSwitch::Named(name)) => write!(f, "Profile::{}", name),
+            Action::Scroll(scroll) if scroll.steps == 1 => write!(f, "Scroll::{:?}", scroll.direction),
+            Action::Scroll(scroll) => write!(f, "Scroll::{:?}({})", scroll.direction, scroll.steps),
+            Action::Move(x, y) => write!(f, "Move({},{})", x, y),
+            Action::DoubleClick(button) => write!(f, "DoubleClick::{}", button.name()),
+            Action::Passthrough => f.write_str("Passthrough"),
+            Action::Disabled => f.write_str("Disabled"),
+        }
+    }
+}
+impl<T: Into<Chord>> From<T> for Action {
+    fn from(value: T) -> Self {
+        Self::Keys(value.into())
+    }
+}
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ActionVisitor)
+    }
 }
-impl Default for KeyMapper {
-    fn default() -> Self {
-        Self {
-            keys: [
-                Key::_1.into(),
-                Key::_2.into(),
-                Key::_3.into(),
-                Key::_4.into(),
-                Key::_5.into(),
-                Key::_6.into(),
-                Key::_7.into(),
-                Key::_8.into(),
-                Key::_9.into(),
-                Key::_0.into(),
-                Key::Minus.into(),
-                Key::Equal.into(),
-            ],
+
+/// Accepts either a string such as `"F1"` or `"Macro::build"`, or an
+/// inline table such as `{ tap = "Esc", hold = "LeftControl" }`.
+struct ActionVisitor;
+impl<'de> Visitor<'de> for ActionVisitor {
+    type Value = Action;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a key name or an action table")
+    }
+
+    fn visit_str<E: Error>(self, action: &str) -> Result<Action, E> {
+        if action == "Passthrough" {
+            Ok(Action::Passthrough)
+        } else if action == "Disabled" {
+            Ok(Action::Disabled)
+        } else if let Some(name) = action.strip_prefix("Macro::") {
+            Ok(Action::Macro(name.to_string()))
+        } else if let Some(name) = action.strip_prefix("Layer::") {
+            Ok(Action::Layer(name.to_string()))
+        } else if let Some(name) = action.strip_prefix("Profile::") {
+            Ok(Action::Profile(ProfileSwitch::from_name(name)))
+        } else if let Some(text) = action.strip_prefix("text:") {
+            Ok(Action::Text(text.to_string()))
+        } else if let Some(scroll) = action.strip_prefix("Scroll::") {
+            scroll.parse().map(Action::Scroll).map_err(E::custom)
+        } else if action.starts_with("Move(") {
+            parse_move(action).map_err(E::custom)
+        } else if let Some(name) = action.strip_prefix("DoubleClick::") {
+            InputMouse::from_name(&format!("Mouse::{}", name))
+                .map(Action::DoubleClick)
+                .ok_or_else(|| E::custom(format!("Unknown mouse button: {}", name)))
+        } else {
+            action.parse().map(Action::Keys).map_err(E::custom)
         }
     }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Action, A::Error> {
+        ActionTable::deserialize(MapAccessDeserializer::new(map))?
+            .into_action()
+            .map_err(A::Error::custom)
+    }
 }
-impl KeyMapper {
-    /// Loads key mappings from a TOML configuration file.
-    ///
-    /// # Arguments
-    ///
-    /// * `path` - Path to the TOML config file
-    ///
-    /// # Returns
-    ///
-    /// * `Ok(KeyMapper)` - Successfully loaded configuration
-    /// * `Err(String)` - Failed to read or parse the file
-    ///
-    /// # Example Config
-    ///
-    /// ```toml
-    /// [keys]
-    /// "1" = "F1"
-    /// "2" = "F2"
-    /// "6" = "KP::_1"
-    /// ```
-    pub fn read_from_file(path: &str) -> Result<KeyMapper, String> {
-        let contents = read_file_contents(path)?;
-        let config: Config = toml::from_str(contents.as_str()).map_err(|e| format!("{}", e))?;
 
-        let mut key_mapper = KeyMapper::default();
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ActionTable {
+    tap: Option<Chord>,
+    hold: Option<Chord>,
+    hold_ms: Option<u64>,
+    taps: Option<Vec<Chord>>,
+    window_ms: Option<u64>,
+    toggle: Option<Chord>,
+    turbo: Option<Chord>,
+    rate_hz: Option<u32>,
+    exec: Option<String>,
+    args: Option<Vec<String>>,
+    env: Option<HashMap<String, String>>,
+    user: Option<String>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+impl ActionTable {
+    /// Each kind of action table, named by its defining field, with the
+    /// fields it accepts.
+    const KINDS: &'static [(&'static str, &'static [&'static str])] = &[
+        ("hold", &["tap", "hold", "hold_ms"]),
+        ("taps", &["taps", "window_ms"]),
+        ("toggle", &["toggle"]),
+        ("turbo", &["turbo", "rate_hz"]),
+        ("exec", &["exec", "args", "env", "user", "uid", "gid"]),
+    ];
+
+    fn fields(&self) -> Vec<&'static str> {
+        let mut fields = Vec::new();
+        if self.tap.is_some() {
+            fields.push("tap");
+        }
+        if self.hold.is_some() {
+            fields.push("hold");
+        }
+        if self.hold_ms.is_some() {
+            fields.push("hold_ms");
+        }
+        if self.taps.is_some() {
+            fields.push("taps");
+        }
+        if self.window_ms.is_some() {
+            fields.push("window_ms");
+        }
+        if self.toggle.is_some() {
+            fields.push("toggle");
+        }
+        if self.turbo.is_some() {
+            fields.push("turbo");
+        }
+        if self.rate_hz.is_some() {
+            fields.push("rate_hz");
+        }
+        if self.exec.is_some() {
+            fields.push("exec");
+        }
+        if self.args.is_some() {
+            fields.push("args");
+        }
+        if self.env.is_some() {
+            fields.push("env");
+        }
+        if self.user.is_some() {
+            fields.push("user");
+        }
+        if self.uid.is_some() {
+            fields.push("uid");
+        }
+        if self.gid.is_some() {
+            fields.push("gid");
+        }
+        fields
+    }
 
-        for (from_key, to_key) in config.keys {
-            let key_num = from_key
-                .parse::<usize>()
-                .map(|i| i - 1)
-                .map_err(|e| format!("{}", e))?;
+    fn into_action(self) -> Result<Action, String> {
+        let fields = self.fields();
+        let Some((kind, allowed)) = Self::KINDS
+            .iter()
+            .find(|(kind, _)| fields.contains(kind))
+        else {
+            let kinds: Vec<_> = Self::KINDS.iter().map(|(kind, _)| format!("`{}`", kind)).collect();
+            return Err(format!("action table needs one of {}", kinds.join(", ")));
+        };
+        if let Some(field) = fields.iter().find(|field| !allowed.contains(field)) {
+            return Err(format!("`{}` cannot be used with `{}`", field, kind));
+        }
 
-            if key_num >= 12 {
-                return Err(format!("Invalid key number: {}", key_num));
+        match self {
+            ActionTable {
+                hold: Some(hold),
+                tap,
+                hold_ms,
+                ..
+            } => Ok(Action::TapHold(TapHold {
+                tap: tap.ok_or("`hold` needs a `tap` key")?,
+                hold,
+                hold_ms: hold_ms.unwrap_or(DEFAULT_HOLD_MS),
+            })),
+            ActionTable {
+                taps: Some(taps),
+                window_ms,
+                ..
+            } => {
+                if taps.is_empty() {
+                    return Err("`taps` needs at least one entry".to_string());
+                }
+                Ok(Action::TapDance(TapDance {
+                    taps,
+                    window_ms: window_ms.unwrap_or(DEFAULT_TAP_WINDOW_MS),
+                }))
+            }
+            ActionTable {
+                toggle: Some(toggle),
+                ..
+            } => Ok(Action::Toggle(toggle)),
+            ActionTable {
+                turbo: Some(turbo),
+                rate_hz,
+                ..
+            } => {
+                let rate_hz = rate_hz.unwrap_or(DEFAULT_TURBO_HZ);
+                if !(1..=MAX_TURBO_HZ).contains(&rate_hz) {
+                    return Err(format!("`rate_hz` must be between 1 and {}", MAX_TURBO_HZ));
+                }
+                Ok(Action::Turbo(Turbo {
+                    chord: turbo,
+                    rate_hz,
+                }))
             }
+            ActionTable {
+                exec: Some(program),
+                args,
+                env,
+                user,
+                uid,
+                gid,
+                ..
+            } => {
+                let run_as = match (user, uid) {
+                    (Some(_), Some(_)) => {
+                        return Err("`user` and `uid` cannot be used together".to_string())
+                    }
+                    (Some(name), None) => {
+                        let user = RunAs::from_name(&name).map_err(|e| e.to_string())?;
+                        let mut user = user.ok_or_else(|| format!("Unknown user: {}", name))?;
+                        if let Some(gid) = gid {
+                            user.gid = gid;
+                        }
+                        Some(user)
+                    }
+                    (None, Some(uid)) => Some(RunAs::from_uid(uid, gid).map_err(|e| e.to_string())?),
+                    (None, None) if gid.is_some() => {
+                        return Err("`gid` needs a `user` or `uid`".to_string())
+                    }
+                    (None, None) => None,
+                };
+                Ok(Action::Exec(Exec {
+                    program,
+                    args: args.unwrap_or_default(),
+                    env: env.unwrap_or_default(),
+                    run_as,
+                }))
+            }
+            _ => unreachable!("kind checked above"),
+        }
+    }
+}
+
+/// How long a tap-hold button must be held before it counts as a hold.
+pub const DEFAULT_HOLD_MS: u64 = 200;
+
+/// How long a tap-dance button waits for another tap.
+pub const DEFAULT_TAP_WINDOW_MS: u64 = 250;
+
+/// How many times a second a turbo button repeats by default.
+pub const DEFAULT_TURBO_HZ: u32 = 10;
+
+/// Fastest allowed turbo rate, so a binding cannot flood uinput.
+pub const MAX_TURBO_HZ: u32 = 50;
+
+/// A dual-role binding, written as `{ tap = "Esc", hold = "LeftControl" }`.
+///
+/// Released before `hold_ms` the button taps `tap`; held longer, or held
+/// while another side button is pressed, it holds `hold` down until released.
+#[derive(Clone)]
+pub struct TapHold {
+    pub(crate) tap: Chord,
+    pub(crate) hold: Chord,
+    pub(crate) hold_ms: u64,
+}
+
+/// A multi-tap binding, written as `{ taps = ["F1", "F2", "F3"] }`.
+///
+/// Each press within `window_ms` of the previous one counts as another
+/// tap. Once the window passes, the chord for that tap count is sent.
+/// Reaching the last entry sends it straight away.
+#[derive(Clone)]
+pub struct TapDance {
+    pub(crate) taps: Vec<Chord>,
+    pub(crate) window_ms: u64,
+}
+
+/// An autofire binding, written as `{ turbo = "A", rate_hz = 20 }`.
+///
+/// While the button is held the chord is pressed and released `rate_hz`
+/// times a second, independently of kernel autorepeat.
+#[derive(Clone)]
+pub struct Turbo {
+    pub(crate) chord: Chord,
+    pub(crate) rate_hz: u32,
+}
+impl Turbo {
+    /// Time the chord spends down, and then up, in each repeat.
+    pub(crate) fn half_period(&self) -> Duration {
+        Duration::from_secs(1) / (self.rate_hz * 2)
+    }
+}
 
-            key_mapper.keys[key_num] = to_key;
+/// A wheel binding, written as `"Scroll::Up(3)"`, or `"Scroll::Up"` for
+/// a single step.
+#[derive(Clone)]
+pub struct Scroll {
+    pub(crate) direction: ScrollDirection,
+    pub(crate) steps: u32,
+}
+impl Scroll {
+    /// The `REL_*` axis the wheel turns on and the signed amount.
+    pub(crate) fn event(&self) -> (c_int, i32) {
+        let steps = self.steps as i32;
+        match self.direction {
+            ScrollDirection::Up => (uinput_sys::REL_WHEEL, steps),
+            ScrollDirection::Down => (uinput_sys::REL_WHEEL, -steps),
+            ScrollDirection::Left => (uinput_sys::REL_HWHEEL, -steps),
+            ScrollDirection::Right => (uinput_sys::REL_HWHEEL, steps),
         }
+    }
+}
+impl FromStr for Scroll {
+    type Err = String;
 
-        Ok(key_mapper)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, steps) = match s.split_once('(') {
+            Some((name, steps)) => {
+                let steps = steps
+                    .strip_suffix(')')
+                    .and_then(|steps| steps.trim().parse::<u32>().ok())
+                    .filter(|&steps| steps > 0)
+                    .ok_or_else(|| format!("Bad step count in `Scroll::{}`, expected e.g. `Scroll::Up(3)`", s))?;
+                (name, steps)
+            }
+            None => (s, 1),
+        };
+        let direction = match name {
+            "Up" => ScrollDirection::Up,
+            "Down" => ScrollDirection::Down,
+            "Left" => ScrollDirection::Left,
+            "Right" => ScrollDirection::Right,
+            _ => return Err(format!("Unknown scroll direction: {}, expected Up, Down, Left or Right", name)),
+        };
+        Ok(Scroll { direction, steps })
     }
+}
 
-    #[cfg(debug_assertions)]
-    pub fn debug_mappings(&self) -> String {
-        let mut result = String::new();
-        for (idx, key) in self.keys.iter().enumerate() {
-            result.push_str(&format!("  Button {} (index {}) -> {}\n", 
-                idx + 1, idx, key.debug_name()));
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScrollDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// Parses `"Move(x,y)"` into a [`Action::Move`].
+fn parse_move(action: &str) -> Result<Action, String> {
+    let bad = || format!("Bad pointer motion `{}`, expected e.g. `Move(10,0)`", action);
+    let (x, y) = action
+        .strip_prefix("Move(")
+        .and_then(|args| args.strip_suffix(')'))
+        .and_then(|args| args.split_once(','))
+        .ok_or_else(bad)?;
+    let x = x.trim().parse().map_err(|_| bad())?;
+    let y = y.trim().parse().map_err(|_| bad())?;
+    Ok(Action::Move(x, y))
+}
+
+/// A command binding, written as
+/// `{ exec = "notify-send", args = ["hi"], env = { KEY = "value" }, user = "me" }`.
+///
+/// The command is started on press and not waited for. `user`, or `uid`
+/// and `gid`, choose who it runs as; otherwise it runs as the daemon does.
+#[derive(Clone)]
+pub struct Exec {
+    pub(crate) program: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) env: HashMap<String, String>,
+    pub(crate) run_as: Option<RunAs>,
+}
+
+impl fmt::Display for Exec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{ exec = {}", quote(&self.program))?;
+        if !self.args.is_empty() {
+            let args: Vec<String> = self.args.iter().map(|arg| quote(arg)).collect();
+            write!(f, ", args = [{}]", args.join(", "))?;
         }
-        result
+        if !self.env.is_empty() {
+            let mut env: Vec<String> = self
+                .env
+                .iter()
+                .map(|(key, value)| format!("{} = {}", quote(key), quote(value)))
+                .collect();
+            env.sort();
+            write!(f, ", env = {{ {} }}", env.join(", "))?;
+        }
+        match &self.run_as {
+            Some(RunAs { name: Some(name), .. }) => write!(f, ", user = {}", quote(name))?,
+            Some(run_as) => write!(f, ", uid = {}, gid = {}", run_as.uid, run_as.gid)?,
+            None => {}
+        }
+        f.write_str(" }")
+    }
+}
+
+/// A scripted sequence of key events, configured under `[macros.<name>]`.
+///
+/// Any key still held when the macro ends (or is cancelled) is released.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Macro {
+    pub(crate) steps: Vec<MacroStep>,
+}
+impl Macro {
+    fn validate(&self, text_settings: &TextSettings) -> Result<(), String> {
+        for step in &self.steps {
+            if let MacroStep::Text(text) = step {
+                check_text(text, text_settings)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_text(text: &str, text_settings: &TextSettings) -> Result<(), String> {
+    match text.chars().find(|c| text_settings.chords_for(*c).is_none()) {
+        Some(c) => Err(format!("cannot type {:?}", c)),
+        None => Ok(()),
     }
 }
 
-fn read_file_contents(path: &str) -> Result<String, String> {
-    fs::read_to_string(path).map_err(|e| format!("{}", e))
+/// A single step of a [`Macro`], written as a one-entry inline table
+/// such as `{ press = "A" }` or `{ delay_ms = 30 }`.
+#[derive(Clone)]
+pub enum MacroStep {
+    /// Press the keys and keep them held.
+    Press(Chord),
+    /// Release previously pressed keys.
+    Release(Chord),
+    /// Press and immediately release the keys.
+    Tap(Chord),
+    /// Wait for the given number of milliseconds.
+    DelayMs(u64),
+    /// Type the text one character at a time using the `[text]` settings.
+    Text(String),
+}
+impl MacroStep {
+    /// The chord the step presses, if any.
+    fn chord(&self) -> Option<&Chord> {
+        match self {
+            MacroStep::Press(chord) | MacroStep::Release(chord) | MacroStep::Tap(chord) => Some(chord),
+            MacroStep::DelayMs(_) | MacroStep::Text(_) => None,
+        }
+    }
+
+    /// Writes the step as a TOML inline table.
+    fn to_toml(&self) -> String {
+        match self {
+            MacroStep::Press(chord) => format!("{{ press = {} }}", quote(&chord.to_string())),
+            MacroStep::Release(chord) => format!("{{ release = {} }}", quote(&chord.to_string())),
+            MacroStep::Tap(chord) => format!("{{ tap = {} }}", quote(&chord.to_string())),
+            MacroStep::DelayMs(ms) => format!("{{ delay_ms = {} }}", ms),
+            MacroStep::Text(text) => format!("{{ text = {} }}", quote(text)),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for MacroStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let step = MacroStepTable::deserialize(deserializer)?;
+        let mut steps = [
+            step.press.map(MacroStep::Press),
+            step.release.map(MacroStep::Release),
+            step.tap.map(MacroStep::Tap),
+            step.delay_ms.map(MacroStep::DelayMs),
+            step.text.map(MacroStep::Text),
+        ]
+        .into_iter()
+        .flatten();
+        match (steps.next(), steps.next()) {
+            (Some(step), None) => Ok(step),
+            _ => Err(D::Error::custom(
+                "macro step needs exactly one of `press`, `release`, `tap`, `delay_ms`, `text`",
+            )),
+        }
+    }
 }
 
+/// A macro step as written: a table with exactly one of these fields.
 #[derive(Deserialize)]
-struct Config {
-    keys: HashMap<String, Input>,
+#[serde(deny_unknown_fields)]
+struct MacroStepTable {
+    press: Option<Chord>,
+    release: Option<Chord>,
+    tap: Option<Chord>,
+    delay_ms: Option<u64>,
+    text: Option<String>,
+}
+
+/// One or more keys sent together for a single button.
+///
+/// Written in the config as key names joined by `+`, e.g.
+/// `"LeftControl+LeftShift+T"`. Keys are pressed in the order given
+/// and released in reverse order.
+#[derive(Clone)]
+pub struct Chord(Vec<Input>);
+impl Deref for Chord {
+    type Target = [Input];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl Chord {
+    pub fn new(inputs: Vec<Input>) -> Chord {
+        Chord(inputs)
+    }
+
+    #[cfg(debug_assertions)]
+    pub fn debug_name(&self) -> String {
+        self.to_string()
+    }
+}
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, input) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", input)?;
+        }
+        Ok(())
+    }
+}
+impl<T: Into<Input>> From<T> for Chord {
+    fn from(value: T) -> Self {
+        Self(vec![value.into()])
+    }
+}
+impl FromStr for Chord {
+    type Err = String;
+
+    fn from_str(chord: &str) -> Result<Self, Self::Err> {
+        chord
+            .split('+')
+            .map(|name| {
+                let name = name.trim();
+                Input::from_name(name).ok_or_else(|| format!("Unknown key name: {name}"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Self)
+    }
+}
+impl<'de> Deserialize<'de> for Chord {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
 }
 
-#[derive(Deserialize, Copy, Clone)]
-#[serde(untagged)]
+/// A single key the virtual keyboard can send.
+///
+/// Accepted names are, in order of preference: the friendly names of
+/// `uinput`'s `Key` and `Misc` keys (`"F1"`, `"VolumeUp"`), keypad keys
+/// prefixed with `KP::`, the kernel's `KEY_*` names (`"KEY_MICMUTE"`), raw
+/// codes written as `"code:113"`, mouse buttons such as `"Mouse::Left"`
+/// and gamepad buttons such as `"Pad::A"`.
+#[derive(Copy, Clone)]
 pub enum Input {
     InputKey(InputKey),
     InputKeyPad(InputKeyPad),
+    InputCode(InputCode),
+    InputMouse(InputMouse),
+    InputPad(InputPad),
 }
 impl Press for Input {}
 impl Release for Input {}
@@ -116,6 +2313,9 @@ impl Kind for Input {
         match self {
             Input::InputKey(k) => k.kind(),
             Input::InputKeyPad(kp) => kp.kind(),
+            Input::InputCode(c) => c.kind(),
+            Input::InputMouse(m) => m.kind(),
+            Input::InputPad(p) => p.kind(),
         }
     }
 }
@@ -124,18 +2324,65 @@ impl Code for Input {
         match self {
             Input::InputKey(k) => k.code(),
             Input::InputKeyPad(kp) => kp.code(),
+            Input::InputCode(c) => c.code(),
+            Input::InputMouse(m) => m.code(),
+            Input::InputPad(p) => p.code(),
         }
     }
 }
+impl PartialEq for Input {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind() && self.code() == other.code()
+    }
+}
 impl Input {
+    /// Which virtual device sends the input.
+    pub fn class(&self) -> key_codes::Class {
+        key_codes::class(self.code() as u16)
+    }
+
+    /// Looks up a key by its config name, e.g. `"F1"`, `"KP::_1"`,
+    /// `"KEY_VOLUMEUP"`, `"code:113"`, `"Mouse::Left"` or `"Pad::A"`.
+    pub fn from_name(name: &str) -> Option<Input> {
+        InputKey::from_name(name)
+            .map(Input::InputKey)
+            .or_else(|| InputKeyPad::from_name(name).map(Input::InputKeyPad))
+            .or_else(|| InputCode::from_name(name).map(Input::InputCode))
+            .or_else(|| InputMouse::from_name(name).map(Input::InputMouse))
+            .or_else(|| InputPad::from_name(name).map(Input::InputPad))
+    }
+
     #[cfg(debug_assertions)]
     pub fn debug_name(&self) -> String {
+        self.to_string()
+    }
+}
+/// Writes the key's config name.
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Input::InputKey(k) => k.debug_name(),
-            Input::InputKeyPad(kp) => kp.debug_name(),
+            Input::InputKey(k) => write!(f, "{:?}", k.0),
+            Input::InputKeyPad(kp) => write!(f, "KP::{:?}", kp.0),
+            Input::InputCode(c) => match key_codes::name_of(c.0) {
+                Some(name) => f.write_str(name),
+                None => write!(f, "code:{}", c.0),
+            },
+            Input::InputMouse(m) => write!(f, "Mouse::{}", m.name()),
+            Input::InputPad(p) => write!(f, "Pad::{}", p.name()),
         }
     }
 }
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let variant = String::deserialize(deserializer)?;
+        Self::from_name(&variant).ok_or_else(|| {
+            D::Error::custom(format!("Invalid keycode - {variant}: cannot deserialize"))
+        })
+    }
+}
 impl From<Key> for Input {
     fn from(value: Key) -> Self {
         Self::InputKey(InputKey(value))
@@ -147,7 +2394,7 @@ impl From<KeyPad> for Input {
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct InputKey(Key);
 impl Deref for InputKey {
     type Target = Key;
@@ -161,6 +2408,19 @@ impl InputKey {
     pub fn debug_name(&self) -> String {
         format!("{:?}", self.0)
     }
+
+    /// Config names of every key on the main keyboard.
+    pub fn names() -> Vec<String> {
+        Key::iter_variants().map(|key| format!("{:?}", key)).collect()
+    }
+
+    /// Looks up a key by its config name, which is the name of the
+    /// `uinput` `Key` variant.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Key::iter_variants()
+            .find(|key| format!("{:?}", key) == name)
+            .map(Self)
+    }
 }
 impl<'de> Deserialize<'de> for InputKey {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -168,119 +2428,13 @@ impl<'de> Deserialize<'de> for InputKey {
         D: serde::Deserializer<'de>,
     {
         let variant = String::deserialize(deserializer)?;
-        Ok(match variant.as_str() {
-            "Reserved" => Self(Key::Reserved),
-            "Esc" => Self(Key::Esc),
-            "_1" => Self(Key::_1),
-            "_2" => Self(Key::_2),
-            "_3" => Self(Key::_3),
-            "_4" => Self(Key::_4),
-            "_5" => Self(Key::_5),
-            "_6" => Self(Key::_6),
-            "_7" => Self(Key::_7),
-            "_8" => Self(Key::_8),
-            "_9" => Self(Key::_9),
-            "_0" => Self(Key::_0),
-            "Minus" => Self(Key::Minus),
-            "Equal" => Self(Key::Equal),
-            "BackSpace" => Self(Key::BackSpace),
-            "Tab" => Self(Key::Tab),
-            "Q" => Self(Key::Q),
-            "W" => Self(Key::W),
-            "E" => Self(Key::E),
-            "R" => Self(Key::R),
-            "T" => Self(Key::T),
-            "Y" => Self(Key::Y),
-            "U" => Self(Key::U),
-            "I" => Self(Key::I),
-            "O" => Self(Key::O),
-            "P" => Self(Key::P),
-            "LeftBrace" => Self(Key::LeftBrace),
-            "RightBrace" => Self(Key::RightBrace),
-            "Enter" => Self(Key::Enter),
-            "LeftControl" => Self(Key::LeftControl),
-            "A" => Self(Key::A),
-            "S" => Self(Key::S),
-            "D" => Self(Key::D),
-            "F" => Self(Key::F),
-            "G" => Self(Key::G),
-            "H" => Self(Key::H),
-            "J" => Self(Key::J),
-            "K" => Self(Key::K),
-            "L" => Self(Key::L),
-            "SemiColon" => Self(Key::SemiColon),
-            "Apostrophe" => Self(Key::Apostrophe),
-            "Grave" => Self(Key::Grave),
-            "LeftShift" => Self(Key::LeftShift),
-            "BackSlash" => Self(Key::BackSlash),
-            "Z" => Self(Key::Z),
-            "X" => Self(Key::X),
-            "C" => Self(Key::C),
-            "V" => Self(Key::V),
-            "B" => Self(Key::B),
-            "N" => Self(Key::N),
-            "M" => Self(Key::M),
-            "Comma" => Self(Key::Comma),
-            "Dot" => Self(Key::Dot),
-            "Slash" => Self(Key::Slash),
-            "RightShift" => Self(Key::RightShift),
-            "LeftAlt" => Self(Key::LeftAlt),
-            "Space" => Self(Key::Space),
-            "CapsLock" => Self(Key::CapsLock),
-            "F1" => Self(Key::F1),
-            "F2" => Self(Key::F2),
-            "F3" => Self(Key::F3),
-            "F4" => Self(Key::F4),
-            "F5" => Self(Key::F5),
-            "F6" => Self(Key::F6),
-            "F7" => Self(Key::F7),
-            "F8" => Self(Key::F8),
-            "F9" => Self(Key::F9),
-            "F10" => Self(Key::F10),
-            "NumLock" => Self(Key::NumLock),
-            "ScrollLock" => Self(Key::ScrollLock),
-            "F11" => Self(Key::F11),
-            "F12" => Self(Key::F12),
-            "RightControl" => Self(Key::RightControl),
-            "SysRq" => Self(Key::SysRq),
-            "RightAlt" => Self(Key::RightAlt),
-            "LineFeed" => Self(Key::LineFeed),
-            "Home" => Self(Key::Home),
-            "Up" => Self(Key::Up),
-            "PageUp" => Self(Key::PageUp),
-            "Left" => Self(Key::Left),
-            "Right" => Self(Key::Right),
-            "End" => Self(Key::End),
-            "Down" => Self(Key::Down),
-            "PageDown" => Self(Key::PageDown),
-            "Insert" => Self(Key::Insert),
-            "Delete" => Self(Key::Delete),
-            "LeftMeta" => Self(Key::LeftMeta),
-            "RightMeta" => Self(Key::RightMeta),
-            "ScrollUp" => Self(Key::ScrollUp),
-            "ScrollDown" => Self(Key::ScrollDown),
-            "F13" => Self(Key::F13),
-            "F14" => Self(Key::F14),
-            "F15" => Self(Key::F15),
-            "F16" => Self(Key::F16),
-            "F17" => Self(Key::F17),
-            "F18" => Self(Key::F18),
-            "F19" => Self(Key::F19),
-            "F20" => Self(Key::F20),
-            "F21" => Self(Key::F21),
-            "F22" => Self(Key::F22),
-            "F23" => Self(Key::F23),
-            "F24" => Self(Key::F24),
-            invalid => {
-                return Err(D::Error::custom(format!(
-                    "Invalid keycode - {invalid}: cannot deserialize"
-                )))
-            }
+        Self::from_name(&variant).ok_or_else(|| {
+            D::Error::custom(format!("Invalid keycode - {variant}: cannot deserialize"))
         })
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub struct InputKeyPad(KeyPad);
 impl Deref for InputKeyPad {
     type Target = KeyPad;
@@ -294,6 +2448,20 @@ impl InputKeyPad {
     pub fn debug_name(&self) -> String {
         format!("KP::{:?}", self.0)
     }
+
+    /// Config names of every keypad key.
+    pub fn names() -> Vec<String> {
+        KeyPad::iter_variants().map(|key| format!("KP::{:?}", key)).collect()
+    }
+
+    /// Looks up a key by its config name, which is the name of the
+    /// `uinput` `KeyPad` variant prefixed with `KP::`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.strip_prefix("KP::")?;
+        KeyPad::iter_variants()
+            .find(|key| format!("{:?}", key) == name)
+            .map(Self)
+    }
 }
 impl<'de> Deserialize<'de> for InputKeyPad {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
@@ -301,34 +2469,248 @@ impl<'de> Deserialize<'de> for InputKeyPad {
         D: serde::Deserializer<'de>,
     {
         let variant = String::deserialize(deserializer)?;
-        Ok(match variant.as_str() {
-            "KP::Asterisk" => Self(KeyPad::Asterisk),
-            "KP::_7" => Self(KeyPad::_7),
-            "KP::_8" => Self(KeyPad::_8),
-            "KP::_9" => Self(KeyPad::_9),
-            "KP::Minus" => Self(KeyPad::Minus),
-            "KP::_4" => Self(KeyPad::_4),
-            "KP::_5" => Self(KeyPad::_5),
-            "KP::_6" => Self(KeyPad::_6),
-            "KP::Plus" => Self(KeyPad::Plus),
-            "KP::_1" => Self(KeyPad::_1),
-            "KP::_2" => Self(KeyPad::_2),
-            "KP::_3" => Self(KeyPad::_3),
-            "KP::_0" => Self(KeyPad::_0),
-            "KP::Dot" => Self(KeyPad::Dot),
-            "KP::AltComma" => Self(KeyPad::AltComma),
-            "KP::Enter" => Self(KeyPad::Enter),
-            "KP::Slash" => Self(KeyPad::Slash),
-            "KP::Equal" => Self(KeyPad::Equal),
-            "KP::PlusMinus" => Self(KeyPad::PlusMinus),
-            "KP::Comma" => Self(KeyPad::Comma),
-            "KP::LeftParen" => Self(KeyPad::LeftParen),
-            "KP::RightParen" => Self(KeyPad::RightParen),
-            invalid => {
-                return Err(D::Error::custom(format!(
-                    "Invalid keycode - {invalid}: cannot deserialize"
-                )))
-            }
+        Self::from_name(&variant).ok_or_else(|| {
+            D::Error::custom(format!("Invalid keycode - {variant}: cannot deserialize"))
         })
     }
 }
+
+/// Any key code from the kernel's `KEY_*` range, for keys `uinput`'s
+/// `Key` and `KeyPad` enums don't cover.
+#[derive(Copy, Clone, PartialEq)]
+pub struct InputCode(u16);
+impl Press for InputCode {}
+impl Release for InputCode {}
+impl Kind for InputCode {
+    fn kind(&self) -> c_int {
+        uinput_sys::EV_KEY
+    }
+}
+impl Code for InputCode {
+    fn code(&self) -> c_int {
+        self.0 as c_int
+    }
+}
+impl InputCode {
+    #[cfg(debug_assertions)]
+    pub fn debug_name(&self) -> String {
+        match key_codes::name_of(self.0) {
+            Some(name) => name.to_string(),
+            None => format!("code:{}", self.0),
+        }
+    }
+
+    /// Friendly names of media and system keys, such as `"VolumeUp"`.
+    pub fn friendly_names() -> Vec<String> {
+        Misc::iter_variants().map(|key| format!("{:?}", key)).collect()
+    }
+
+    /// Looks up a key by a `uinput` `Misc` name (`"VolumeUp"`), a kernel
+    /// name (`"KEY_VOLUMEUP"`) or a raw code (`"code:115"`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        if let Some(code) = name.strip_prefix("code:") {
+            let code = code.parse::<u16>().ok()?;
+            return (code <= key_codes::KEY_MAX).then_some(Self(code));
+        }
+        if let Some(misc) = Misc::iter_variants().find(|key| format!("{:?}", key) == name) {
+            return Some(Self(misc.code() as u16));
+        }
+        key_codes::by_name(name).map(Self)
+    }
+}
+
+/// A mouse button, written as `"Mouse::Left"`, `"Mouse::Back"` and so on.
+#[derive(Copy, Clone, PartialEq)]
+pub struct InputMouse(u16);
+impl Press for InputMouse {}
+impl Release for InputMouse {}
+impl Kind for InputMouse {
+    fn kind(&self) -> c_int {
+        uinput_sys::EV_KEY
+    }
+}
+impl Code for InputMouse {
+    fn code(&self) -> c_int {
+        self.0 as c_int
+    }
+}
+impl InputMouse {
+    /// Config names of every mouse button.
+    pub fn names() -> Vec<String> {
+        MOUSE_BUTTONS.iter().map(|(name, _)| format!("Mouse::{}", name)).collect()
+    }
+
+    /// Looks up a button by its config name, one of [`MOUSE_BUTTONS`]
+    /// prefixed with `Mouse::`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.strip_prefix("Mouse::")?;
+        MOUSE_BUTTONS
+            .iter()
+            .find(|(button, _)| *button == name)
+            .map(|(_, code)| Self(*code))
+    }
+
+    /// The button's name without the `Mouse::` prefix.
+    fn name(&self) -> &'static str {
+        MOUSE_BUTTONS
+            .iter()
+            .find(|(_, code)| *code == self.0)
+            .map_or("?", |(name, _)| name)
+    }
+}
+
+/// A button of the virtual gamepad, written as `"Pad::A"`, `"Pad::DpadUp"`
+/// and so on.
+#[derive(Copy, Clone, PartialEq)]
+pub struct InputPad(u16);
+impl Press for InputPad {}
+impl Release for InputPad {}
+impl Kind for InputPad {
+    fn kind(&self) -> c_int {
+        uinput_sys::EV_KEY
+    }
+}
+impl Code for InputPad {
+    fn code(&self) -> c_int {
+        self.0 as c_int
+    }
+}
+impl InputPad {
+    /// Config names of every gamepad button.
+    pub fn names() -> Vec<String> {
+        PAD_BUTTONS.iter().map(|(name, _)| format!("Pad::{}", name)).collect()
+    }
+
+    /// Looks up a button by its config name, one of [`PAD_BUTTONS`]
+    /// prefixed with `Pad::`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.strip_prefix("Pad::")?;
+        PAD_BUTTONS
+            .iter()
+            .find(|(button, _)| *button == name)
+            .map(|(_, code)| Self(*code))
+    }
+
+    /// The button's name without the `Pad::` prefix.
+    fn name(&self) -> &'static str {
+        PAD_BUTTONS
+            .iter()
+            .find(|(_, code)| *code == self.0)
+            .map_or("?", |(name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn errors(config: &str) -> Vec<ConfigError> {
+        match KeyMapper::from_toml_str(config) {
+            Ok(_) => panic!("config should not load"),
+            Err(errors) => errors.errors,
+        }
+    }
+
+    #[test]
+    fn buttons_are_checked_against_the_device() {
+        let config = "[device]\npreset = \"naga-hex\"\n\n[keys]\n6 = \"A\"\n7 = \"B\"\n";
+        let errors = errors(config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key.as_deref(), Some("keys.7"));
+        assert_eq!((errors[0].line, errors[0].column), (Some(6), Some(1)));
+        assert!(errors[0].message.contains("numbered 1 to 6"));
+
+        assert!(KeyMapper::from_toml_str("[keys]\n12 = \"A\"\n").is_ok());
+    }
+
+    #[test]
+    fn errors_point_at_the_entry() {
+        let config = concat!(
+            "[keys]\n",
+            "1 = \"A\"\n",
+            "\n",
+            "[profiles.wow.keys]\n",
+            "  3 = \"Entr\"\n",
+            "\n",
+            "[macros.greet]\n",
+            "steps = [{ tap = \"H\" }]\n",
+        );
+        let errors = errors(config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key.as_deref(), Some("profiles.wow.keys.3"));
+        assert_eq!((errors[0].line, errors[0].column), (Some(5), Some(3)));
+        assert_eq!(errors[0].suggestion.as_deref(), Some("Enter"));
+        assert_eq!(
+            errors[0].to_string(),
+            "5:3: profiles.wow.keys.3: Unknown key name: Entr (did you mean `Enter`?)"
+        );
+    }
+
+    #[test]
+    fn errors_inside_arrays_of_tables_are_located() {
+        let config = concat!(
+            "[focus]\n",
+            "source = \"file\"\n",
+            "path = \"/tmp/focus\"\n",
+            "\n",
+            "[[rules]]\n",
+            "class = \"a\"\n",
+            "profile = \"default\"\n",
+            "\n",
+            "[[rules]]\n",
+            "class = \"b\"\n",
+            "profile = \"missing\"\n",
+        );
+        let errors = errors(config);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].key.as_deref(), Some("rules.1.profile"));
+        assert_eq!((errors[0].line, errors[0].column), (Some(11), Some(1)));
+    }
+
+    #[test]
+    fn errors_are_sorted_and_suggestions_need_to_be_close() {
+        let config = concat!(
+            "[device]\n",
+            "preset = \"naga-trinty\"\n",
+            "\n",
+            "[keys]\n",
+            "2 = \"Xyzzy\"\n",
+            "1 = \"macro:nope\"\n",
+        );
+        let errors = errors(config);
+        let lines: Vec<_> = errors.iter().map(|error| error.line).collect();
+        assert_eq!(lines, [Some(2), Some(5), Some(6)]);
+        assert_eq!(errors[0].suggestion.as_deref(), Some("naga-trinity"));
+        assert_eq!(errors[1].suggestion, None);
+    }
+
+    #[test]
+    fn syntax_errors_have_a_line_and_column() {
+        let errors = errors("[keys]\n1 = \"A\"\n2 = \n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, Some(3));
+        assert!(errors[0].column.is_some());
+        assert!(!errors[0].message.contains("at line"));
+    }
+
+    #[test]
+    fn raw_codes_go_up_to_key_max() {
+        assert!(KeyMapper::from_toml_str("[keys]\n1 = \"code:767\"\n").is_ok());
+        assert_eq!(errors("[keys]\n1 = \"code:768\"\n").len(), 1);
+
+        let unnamed = KeyMapper::from_toml_str("[keys]\n1 = \"code:251\"\n").unwrap();
+        assert!(!unnamed.uses_pointer() && !unnamed.uses_gamepad());
+        assert!(KeyMapper::from_toml_str("[keys]\n1 = \"code:272\"\n").unwrap().uses_pointer());
+        assert!(KeyMapper::from_toml_str("[keys]\n1 = \"code:704\"\n").unwrap().uses_gamepad());
+    }
+
+    #[test]
+    fn passthrough_is_off_by_default() {
+        let key_mapper = KeyMapper::default();
+        assert!(!key_mapper.device().passthrough());
+        assert!(key_mapper.to_toml().contains("\npassthrough = false\n"));
+
+        let key_mapper = KeyMapper::from_toml_str("[device]\npassthrough = true\n").unwrap();
+        assert!(key_mapper.device().passthrough());
+    }
+}
@@ -0,0 +1,140 @@
+//! Keyboard layouts used to type text through the virtual keyboard.
+//!
+//! A [`Layout`] knows which keys produce a character on the layout the
+//! desktop is using. Characters the layout cannot produce are entered
+//! with the configured [`UnicodeEntry`] method instead.
+
+use crate::key_map::{Chord, Input};
+use serde::Deserialize;
+use std::sync::Arc;
+use uinput::event::keyboard::Key;
+
+/// Maps characters to the keys that type them.
+pub trait Layout: Send + Sync {
+    /// Returns the keys that type `c`, or `None` if the layout has no key for it.
+    fn chord_for(&self, c: char) -> Option<Chord>;
+
+    /// The layout's config name.
+    fn name(&self) -> &str {
+        "custom"
+    }
+}
+
+/// Looks up a built-in layout by its config name.
+pub fn from_name(name: &str) -> Option<Arc<dyn Layout>> {
+    match name {
+        "us" => Some(Arc::new(UsLayout)),
+        _ => None,
+    }
+}
+
+/// The standard US QWERTY layout.
+pub struct UsLayout;
+impl Layout for UsLayout {
+    fn name(&self) -> &str {
+        "us"
+    }
+
+    fn chord_for(&self, c: char) -> Option<Chord> {
+        let (key, shift) = match c {
+            'a'..='z' => (letter(c)?, false),
+            'A'..='Z' => (letter(c)?, true),
+            '1'..='9' | '0' => (Input::from_name(&format!("_{}", c))?, false),
+            ' ' => (Key::Space.into(), false),
+            '\n' => (Key::Enter.into(), false),
+            '\t' => (Key::Tab.into(), false),
+            '`' => (Key::Grave.into(), false),
+            '~' => (Key::Grave.into(), true),
+            '!' => (Key::_1.into(), true),
+            '@' => (Key::_2.into(), true),
+            '#' => (Key::_3.into(), true),
+            '$' => (Key::_4.into(), true),
+            '%' => (Key::_5.into(), true),
+            '^' => (Key::_6.into(), true),
+            '&' => (Key::_7.into(), true),
+            '*' => (Key::_8.into(), true),
+            '(' => (Key::_9.into(), true),
+            ')' => (Key::_0.into(), true),
+            '-' => (Key::Minus.into(), false),
+            '_' => (Key::Minus.into(), true),
+            '=' => (Key::Equal.into(), false),
+            '+' => (Key::Equal.into(), true),
+            '[' => (Key::LeftBrace.into(), false),
+            '{' => (Key::LeftBrace.into(), true),
+            ']' => (Key::RightBrace.into(), false),
+            '}' => (Key::RightBrace.into(), true),
+            '\\' => (Key::BackSlash.into(), false),
+            '|' => (Key::BackSlash.into(), true),
+            ';' => (Key::SemiColon.into(), false),
+            ':' => (Key::SemiColon.into(), true),
+            '\'' => (Key::Apostrophe.into(), false),
+            '"' => (Key::Apostrophe.into(), true),
+            ',' => (Key::Comma.into(), false),
+            '<' => (Key::Comma.into(), true),
+            '.' => (Key::Dot.into(), false),
+            '>' => (Key::Dot.into(), true),
+            '/' => (Key::Slash.into(), false),
+            '?' => (Key::Slash.into(), true),
+            _ => return None,
+        };
+
+        Some(if shift {
+            Chord::new(vec![Key::LeftShift.into(), key])
+        } else {
+            key.into()
+        })
+    }
+}
+
+fn letter(c: char) -> Option<Input> {
+    Input::from_name(&c.to_ascii_uppercase().to_string())
+}
+
+/// How to enter characters the layout has no key for.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeEntry {
+    /// Ctrl+Shift+U, the hex code point, then Space (GTK and IBus).
+    CtrlShiftU,
+    /// Don't type such characters; configs using them are rejected.
+    None,
+}
+
+/// Everything needed to turn text into key presses.
+#[derive(Clone)]
+pub struct TextSettings {
+    pub(crate) layout: Arc<dyn Layout>,
+    pub(crate) unicode: UnicodeEntry,
+}
+impl Default for TextSettings {
+    fn default() -> Self {
+        Self {
+            layout: Arc::new(UsLayout),
+            unicode: UnicodeEntry::CtrlShiftU,
+        }
+    }
+}
+impl TextSettings {
+    /// Returns the chords to tap, in order, to type `c`.
+    pub fn chords_for(&self, c: char) -> Option<Vec<Chord>> {
+        if let Some(chord) = self.layout.chord_for(c) {
+            return Some(vec![chord]);
+        }
+
+        match self.unicode {
+            UnicodeEntry::CtrlShiftU => {
+                let mut chords = vec![Chord::new(vec![
+                    Key::LeftControl.into(),
+                    Key::LeftShift.into(),
+                    Key::U.into(),
+                ])];
+                for digit in format!("{:x}", c as u32).chars() {
+                    chords.push(self.layout.chord_for(digit)?);
+                }
+                chords.push(Key::Space.into());
+                Some(chords)
+            }
+            UnicodeEntry::None => None,
+        }
+    }
+}
@@ -1,7 +1,8 @@
 //! Config 2014 Naga Library
 //!
 //! This library provides functionality to remap the 12 side buttons on a
-//! Razer Naga 2014 mouse to configurable keyboard keys on Linux.
+//! Razer Naga 2014 mouse to configurable keyboard keys on Linux. Other
+//! mice are picked with a [`device`] preset or matcher.
 //!
 //! # Example
 //!
@@ -9,37 +10,76 @@
 //! use config_2014_naga::{key_map::KeyMapper, run_loop};
 //! use std::sync::{Arc, atomic::AtomicBool};
 //!
-//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! # fn main() -> Result<(), config_2014_naga::Error> {
 //! let key_mapper = KeyMapper::default();
 //! let running = Arc::new(AtomicBool::new(true));
 //! run_loop(key_mapper, running)?;
 //! # Ok(())
 //! # }
 //! ```
+//!
+//! # Errors
+//!
+//! Everything returns [`Error`], so callers can tell the failures apart:
+//!
+//! ```no_run
+//! use config_2014_naga::{key_map::KeyMapper, run_loop_blocking, Error};
+//!
+//! match run_loop_blocking(KeyMapper::default()) {
+//!     Err(Error::PermissionDenied { path }) => {
+//!         eprintln!("Cannot open {}; add yourself to the input group", path.display())
+//!     }
+//!     Err(e) => eprintln!("{}", e),
+//!     Ok(()) => {}
+//! }
+//! ```
 
+pub mod control;
+pub mod device;
+mod error;
 pub mod event_mapper;
+pub mod exec;
+pub mod focus;
+pub mod hotplug;
 pub mod input_device;
+pub mod key_codes;
 pub mod key_map;
+pub mod layout;
+mod macro_player;
 pub mod naga;
+mod poll;
+pub mod reload;
 
-use std::error::Error;
+use std::os::unix::io::AsRawFd;
 use std::thread;
 use std::time::Duration;
 use std::sync::{
-    Arc,
+    Arc, Mutex,
     atomic::{AtomicBool, Ordering}
 };
 
+use crate::hotplug::{Uevent, Uevents};
 use crate::key_map::KeyMapper;
+use crate::reload::SharedKeyMapper;
+
+pub use crate::error::Error;
+
+/// How long to wait before looking for the Naga again when uevents are
+/// unavailable.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to give udev to set up a new device node.
+const SETTLE_TIME: Duration = Duration::from_millis(200);
 
 /// Perform a single attach-and-map cycle.
 ///
 /// This is useful for testing or higher-level control loops.
-pub fn run_once(key_mapper: &KeyMapper) -> Result<(), Box<dyn Error>> {
-    let mut device = input_device::create()?;
-    let naga = naga::Naga::new()?;
+pub fn run_once(key_mapper: &KeyMapper) -> Result<(), Error> {
+    let device = Arc::new(Mutex::new(input_device::create(key_mapper.virtual_device())?));
+    let naga = naga::Naga::new(key_mapper.device(), key_mapper.grabs_pointer())?;
     let running = Arc::new(AtomicBool::new(true));
-    event_mapper::map_events(key_mapper.clone(), naga, &mut device, running)?;
+    let key_mapper = SharedKeyMapper::new(key_mapper.clone());
+    event_mapper::map_events(&key_mapper, naga, device, running)?;
     Ok(())
 }
 
@@ -53,31 +93,99 @@ pub fn run_once(key_mapper: &KeyMapper) -> Result<(), Box<dyn Error>> {
 /// # Notes
 ///
 /// - Blocks until `running` is set to false
-/// - Will exit cleanly within ~50ms of setting running to false
+/// - Waits for the Naga while it is unplugged, attaching as soon as the
+///   kernel reports it, but returns [`Error::PermissionDenied`] if input
+///   devices cannot be opened when it starts
+/// - Sleeps in `poll` between events, so an idle mouse costs no CPU
+/// - Will exit cleanly within a second of setting running to false; use
+///   [`run_loop_shared`] and call [`SharedKeyMapper::wake`] afterwards to
+///   exit immediately
 /// - CLI can pass `Arc::new(AtomicBool::new(true))` to run indefinitely
-pub fn run_loop(key_mapper: KeyMapper, running: Arc<AtomicBool>) -> Result<(), Box<dyn Error>> {
-    let mut device = input_device::create()?;
+pub fn run_loop(key_mapper: KeyMapper, running: Arc<AtomicBool>) -> Result<(), Error> {
+    run_loop_shared(Arc::new(SharedKeyMapper::new(key_mapper)), running)
+}
+
+/// Like `run_loop`, but follows `key_mapper` as it is replaced at runtime,
+/// e.g. by [`reload::watch`]. Held keys are released on every switch.
+pub fn run_loop_shared(
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+) -> Result<(), Error> {
+    let uevents = match Uevents::kernel() {
+        Ok(uevents) => Some(uevents),
+        Err(_err) => {
+            #[cfg(debug_assertions)]
+            eprintln!("Not listening for uevents ({}), rescanning every second", _err);
+            None
+        }
+    };
+    run_loop_hotplug(key_mapper, running, uevents)
+}
+
+/// Like `run_loop_shared`, but learns that input devices were plugged in
+/// from `uevents`, e.g. a [`Uevents::synthetic`] source in tests. Without
+/// a source it looks for the Naga every second.
+pub fn run_loop_hotplug(
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+    mut uevents: Option<Uevents>,
+) -> Result<(), Error> {
+    let mut identity = key_mapper.virtual_device();
+    let device = Arc::new(Mutex::new(input_device::create(&identity)?));
+    let mut first_scan = true;
 
     while running.load(Ordering::SeqCst) {
-        match naga::Naga::new() {
+        // A reloaded `[virtual_device]` replaces the virtual keyboard
+        if key_mapper.virtual_device() != identity {
+            identity = key_mapper.virtual_device();
+            *device.lock().unwrap_or_else(|e| e.into_inner()) = input_device::create(&identity)?;
+        }
+
+        // Only events from after this scan matter; drop the rest, including
+        // any that overflowed the socket while the Naga was attached
+        if let Some(uevents) = uevents.as_mut() {
+            let _ = uevents.read();
+        }
+
+        let settings = key_mapper.device();
+        let pointer = key_mapper.grabs_pointer();
+        let first = std::mem::replace(&mut first_scan, false);
+        match naga::Naga::new(&settings, pointer) {
             Ok(dev) => {
                 #[cfg(debug_assertions)]
                 eprintln!("Attached to naga");
 
                 // Pass running flag so map_events can exit cleanly
-                if let Err(e) = event_mapper::map_events(key_mapper.clone(), dev, &mut device, running.clone()) {
-                    eprintln!("Error mapping events: {}", e);
+                match event_mapper::map_events(&key_mapper, dev, device.clone(), running.clone()) {
+                    Ok(()) => {}
+                    Err(Error::DeviceRemoved) => println!("Naga unplugged, waiting for it to return"),
+                    Err(e) => eprintln!("Error mapping events: {}", e),
+                }
+
+                // A reloaded `[device]` may describe a mouse that is
+                // already plugged in or need the pointer grabbed, and a new
+                // `[virtual_device]` needs the virtual keyboard replaced
+                if key_mapper.device() != settings
+                    || key_mapper.grabs_pointer() != pointer
+                    || key_mapper.virtual_device() != identity
+                {
+                    continue;
                 }
             }
+            // Retrying won't help until the permissions are fixed, but a
+            // node added later with a stricter mode than the Naga's is no
+            // reason to stop waiting for it
+            Err(err @ Error::PermissionDenied { .. }) if first => return Err(err),
+            Err(err @ Error::PermissionDenied { .. }) => eprintln!("{}", err),
             Err(_err) => {
                 #[cfg(debug_assertions)]
                 eprintln!("Error looking for naga: {}", _err);
             }
         }
 
-        // Only sleep if still running (avoids delay on shutdown)
+        // Only wait if still running (avoids delay on shutdown)
         if running.load(Ordering::SeqCst) {
-            thread::sleep(Duration::from_secs(1));
+            wait_for_device(&key_mapper, &running, uevents.as_mut())?;
         }
     }
 
@@ -87,11 +195,132 @@ pub fn run_loop(key_mapper: KeyMapper, running: Arc<AtomicBool>) -> Result<(), B
     Ok(())
 }
 
+/// Waits until an input device may have been plugged in, `running` is
+/// cleared, or the loop is woken.
+fn wait_for_device(
+    key_mapper: &SharedKeyMapper,
+    running: &AtomicBool,
+    uevents: Option<&mut Uevents>,
+) -> Result<(), Error> {
+    let Some(uevents) = uevents else {
+        poll::wait(&[], key_mapper.waker(), Some(RETRY_DELAY))?;
+        return Ok(());
+    };
+
+    while running.load(Ordering::SeqCst) {
+        poll::wait(&[uevents.as_raw_fd()], key_mapper.waker(), Some(poll::RUNNING_CHECK))?;
+        match uevents.read() {
+            Ok(events) if !events.iter().any(Uevent::is_event_node_added) => continue,
+            Ok(_) => {}
+            // Events were dropped, and the Naga may have been among them
+            Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {}
+            Err(e) => return Err(e.into()),
+        }
+        // Let udev finish setting up the new node before opening it
+        thread::sleep(SETTLE_TIME);
+        return Ok(());
+    }
+    Ok(())
+}
+
 /// Backward-compatible version of `run_loop` for CLI usage
 ///
 /// This simply creates a `running` flag that is always true, so the loop
 /// never exits.
-pub fn run_loop_blocking(key_mapper: KeyMapper) -> Result<(), Box<dyn Error>> {
+pub fn run_loop_blocking(key_mapper: KeyMapper) -> Result<(), Error> {
     let running = Arc::new(AtomicBool::new(true));
     run_loop(key_mapper, running)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hotplug::Uevent;
+    use std::time::Instant;
+
+    fn event(action: &str, subsystem: &str, devname: &str) -> Uevent {
+        Uevent {
+            action: action.to_string(),
+            devpath: "/devices/virtual/input/input7/event5".to_string(),
+            subsystem: subsystem.to_string(),
+            devname: Some(devname.to_string()),
+        }
+    }
+
+    // Matches no device, so the loop keeps waiting for one
+    fn missing_device() -> Arc<SharedKeyMapper> {
+        let config = "[device]\npath = \"/nonexistent/naga-event-kbd\"\n";
+        Arc::new(SharedKeyMapper::new(KeyMapper::from_toml_str(config).unwrap()))
+    }
+
+    #[test]
+    fn waits_for_an_event_node_to_appear() {
+        let (mut uevents, sender) = Uevents::synthetic().unwrap();
+        let key_mapper = missing_device();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let waiting = {
+            let (key_mapper, running) = (key_mapper.clone(), running.clone());
+            thread::spawn(move || wait_for_device(&key_mapper, &running, Some(&mut uevents)))
+        };
+        sender.send(&event("remove", "input", "input/event5")).unwrap();
+        sender.send(&event("add", "input", "input/mouse2")).unwrap();
+        sender.send(&event("add", "hidraw", "hidraw3")).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(!waiting.is_finished());
+
+        sender.send(&event("add", "input", "input/event5")).unwrap();
+        let start = Instant::now();
+        waiting.join().unwrap().unwrap();
+        assert!(start.elapsed() < SETTLE_TIME + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn stops_waiting_when_running_is_cleared() {
+        let (mut uevents, _sender) = Uevents::synthetic().unwrap();
+        let key_mapper = missing_device();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let waiting = {
+            let (key_mapper, running) = (key_mapper.clone(), running.clone());
+            thread::spawn(move || wait_for_device(&key_mapper, &running, Some(&mut uevents)))
+        };
+        thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        running.store(false, Ordering::SeqCst);
+        key_mapper.wake();
+        waiting.join().unwrap().unwrap();
+        assert!(start.elapsed() < poll::RUNNING_CHECK);
+    }
+
+    #[test]
+    fn run_loop_hotplug_survives_uevents_and_stops() {
+        let (uevents, sender) = Uevents::synthetic().unwrap();
+        let key_mapper = missing_device();
+        let running = Arc::new(AtomicBool::new(true));
+
+        let looping = {
+            let (key_mapper, running) = (key_mapper.clone(), running.clone());
+            thread::spawn(move || run_loop_hotplug(key_mapper, running, Some(uevents)))
+        };
+        thread::sleep(Duration::from_millis(100));
+        if looping.is_finished() {
+            match looping.join().unwrap() {
+                // No /dev/uinput or input devices to use here
+                Err(Error::UinputUnavailable(_) | Error::PermissionDenied { .. }) => return,
+                result => panic!("run loop ended early: {:?}", result.err()),
+            }
+        }
+
+        // Each new node sends the loop looking for the Naga again
+        for _ in 0..3 {
+            sender.send(&event("add", "input", "input/event5")).unwrap();
+            thread::sleep(SETTLE_TIME + Duration::from_millis(100));
+            assert!(!looping.is_finished());
+        }
+
+        running.store(false, Ordering::SeqCst);
+        key_mapper.wake();
+        looping.join().unwrap().unwrap();
+    }
+}
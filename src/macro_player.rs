@@ -0,0 +1,132 @@
+//! Background playback of configured macros.
+//!
+//! Each running macro gets its own worker thread so that delays between
+//! steps never block the event loop in `map_events`.
+
+use crate::input_device::{press, release, tap, Output};
+use crate::key_map::{Input, Macro, MacroStep};
+use crate::layout::TextSettings;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use uinput::Error;
+
+/// Handle to a macro playing on a worker thread.
+pub(crate) struct MacroPlayer {
+    cancelled: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl MacroPlayer {
+    /// Starts playing `macro_` on a new worker thread.
+    pub(crate) fn start(
+        name: &str,
+        macro_: Arc<Macro>,
+        text: TextSettings,
+        device: Arc<Mutex<Output>>,
+    ) -> std::io::Result<MacroPlayer> {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let worker_cancelled = cancelled.clone();
+        let worker_name = name.to_string();
+
+        let handle = thread::Builder::new()
+            .name(format!("macro-{}", name))
+            .spawn(move || {
+                if let Err(e) = play(&macro_, &text, &device, &worker_cancelled) {
+                    eprintln!("Error playing macro {}: {}", worker_name, e);
+                }
+            })?;
+
+        Ok(MacroPlayer { cancelled, handle })
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Stops the macro and waits for it to release any held keys.
+    pub(crate) fn cancel(self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.handle.thread().unpark();
+        let _ = self.handle.join();
+    }
+}
+
+fn play(
+    macro_: &Macro,
+    text: &TextSettings,
+    device: &Mutex<Output>,
+    cancelled: &AtomicBool,
+) -> Result<(), Error> {
+    let mut held: Vec<Input> = Vec::new();
+    let result = play_steps(macro_, text, device, cancelled, &mut held);
+
+    // Never leave keys stuck down, whether we finished, were cancelled or failed
+    let mut device = lock(device);
+    for input in held.iter().rev() {
+        device.release(input)?;
+    }
+    device.synchronize()?;
+
+    result
+}
+
+fn play_steps(
+    macro_: &Macro,
+    text_settings: &TextSettings,
+    device: &Mutex<Output>,
+    cancelled: &AtomicBool,
+    held: &mut Vec<Input>,
+) -> Result<(), Error> {
+    for step in &macro_.steps {
+        if cancelled.load(Ordering::SeqCst) {
+            break;
+        }
+
+        match step {
+            MacroStep::Press(chord) => {
+                press(&mut lock(device), chord)?;
+                held.extend(chord.iter().copied());
+            }
+            MacroStep::Release(chord) => {
+                release(&mut lock(device), chord)?;
+                held.retain(|input| !chord.contains(input));
+            }
+            MacroStep::Tap(chord) => tap(&mut lock(device), chord)?,
+            MacroStep::DelayMs(ms) => sleep(Duration::from_millis(*ms), cancelled),
+            MacroStep::Text(text) => {
+                for chords in text.chars().filter_map(|c| text_settings.chords_for(c)) {
+                    if cancelled.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Hold the lock for the whole character so a Unicode
+                    // entry sequence isn't interleaved with other keys
+                    let mut device = lock(device);
+                    for chord in &chords {
+                        tap(&mut device, chord)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sleeps for `duration`, waking early if the macro is cancelled.
+fn sleep(duration: Duration, cancelled: &AtomicBool) {
+    let deadline = Instant::now() + duration;
+    while !cancelled.load(Ordering::SeqCst) {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        thread::park_timeout(deadline - now);
+    }
+}
+
+fn lock(device: &Mutex<Output>) -> std::sync::MutexGuard<'_, Output> {
+    device.lock().unwrap_or_else(|e| e.into_inner())
+}
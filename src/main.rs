@@ -6,14 +6,31 @@
 //!
 //! Run with default key mapping (1-0, minus, equal):
 //! ```bash
-//! config-2014-naga
+//! config-2014-naga run
 //! ```
 //!
-//! Run with custom TOML config file:
+//! Run with custom TOML config file, optionally starting on a profile other
+//! than the default one:
 //! ```bash
-//! config-2014-naga config.toml
+//! config-2014-naga run --config config.toml --profile wow
 //! ```
 //!
+//! The config file is reloaded when it changes or on `SIGHUP`. The older
+//! `config-2014-naga [config.toml [profile]]` form still works.
+//!
+//! Other commands, none of which need root except where noted:
+//! ```bash
+//! config-2014-naga check config.toml        # validate a config
+//! config-2014-naga list-keys [--all]        # every accepted key name
+//! config-2014-naga list-devices             # input devices (root)
+//! config-2014-naga dump-config config.toml  # the effective config
+//! config-2014-naga monitor                  # print raw Naga events (root)
+//! config-2014-naga profile next             # switch the running daemon's profile
+//! config-2014-naga control status           # talk to the control socket
+//! ```
+//!
+//! Every command accepts `--help`.
+//!
 //! # Configuration
 //!
 //! Create a TOML file to customize key mappings:
@@ -22,11 +39,27 @@
 //! "1" = "F1"
 //! "2" = "F2"
 //! "3" = "LeftShift"
+//! "4" = "LeftControl+LeftShift+T"
+//!
+//! # Another mouse, see `list-devices` for the presets
+//! [device]
+//! preset = "naga-trinity"
 //! ```
 
 use std::env;
 use std::error::Error;
-use config_2014_naga::{key_map::KeyMapper, run_loop_blocking};
+use std::io::{self, Write};
+use std::process;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use config_2014_naga::{control, device, focus, key_codes, naga, reload, run_loop_shared};
+use config_2014_naga::naga::Interface;
+use config_2014_naga::Error as NagaError;
+use config_2014_naga::key_map::{InputCode, InputKey, InputKeyPad, InputMouse, InputPad, KeyMapper};
+use evdev_rs::enums::EventCode;
+use serde_json::{json, Value};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const NAME: &str = env!("CARGO_PKG_NAME");
@@ -42,24 +75,462 @@ macro_rules! debug_println {
     };
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    println!("{}-v{}", NAME, VERSION);
+const USAGE: &str = "\
+Usage: config-2014-naga <command> [options]
+
+Commands:
+  run            Remap the Naga's side buttons (the default)
+  check          Validate a config file
+  list-keys      Print every key name a config can use
+  list-devices   Print the input devices and which one is the Naga
+  dump-config    Print the effective config, defaults included
+  monitor        Print the Naga's raw button events
+  profile        Switch the running daemon's profile
+  control        Send a command to the running daemon
+
+Run `config-2014-naga <command> --help` for a command's options.";
+
+const RUN_USAGE: &str = "\
+Usage: config-2014-naga run [--config PATH] [--profile NAME]
+
+Remaps the side buttons until stopped. Without --config the buttons send
+1-0, Minus and Equal. The config is reloaded when it changes.";
+
+const CHECK_USAGE: &str = "\
+Usage: config-2014-naga check PATH
+
+Loads the config the same way `run` does and reports every problem in it,
+each with its line and column. Does not open any device.";
 
-    let args: Vec<String> = env::args().collect();
+const LIST_KEYS_USAGE: &str = "\
+Usage: config-2014-naga list-keys [--all]
 
-    let (key_mapper, config_source) = match args.len() {
-        2 => {
-            let mapper = KeyMapper::read_from_file(&args[1])?;
-            (mapper, format!("file: {}", args[1]))
+Prints the key names accepted in configs. --all adds every kernel KEY_* name.";
+
+const LIST_DEVICES_USAGE: &str = "\
+Usage: config-2014-naga list-devices [--config PATH]
+
+Prints the /dev/input event devices that can be opened, marking the one the
+config's [device] section matches (by default the Naga 2014's side buttons).
+Lists the built-in device presets too.";
+
+const DUMP_CONFIG_USAGE: &str = "\
+Usage: config-2014-naga dump-config [PATH]
+
+Prints the config at PATH, or the default mapping, with every default filled in.";
+
+const MONITOR_USAGE: &str = "\
+Usage: config-2014-naga monitor [--config PATH] [--pointer]
+
+Grabs the Naga, or the device in the config's [device] section, and prints
+its events without remapping them. --pointer grabs the pointer interface as
+well, which stops the cursor moving. Stop with Ctrl+C.
+The daemon must not be running.";
+
+const PROFILE_USAGE: &str = "\
+Usage: config-2014-naga profile [--socket PATH | --config PATH] next|previous
+
+Switches the running daemon to the next or previous profile through its
+control socket: PATH, the socket the config's [control] section sets, or
+/run/config-2014-naga.sock. Sending the daemon SIGUSR1 or SIGUSR2 does the
+same without the socket.";
+
+const CONTROL_USAGE: &str = "\
+Usage: config-2014-naga control [--socket PATH | --config PATH] COMMAND [PROFILE]
+       config-2014-naga control [--socket PATH | --config PATH] JSON
+
+Sends a command to the running daemon's control socket and prints the reply.
+The socket is PATH, the one the config's [control] section sets, or
+/run/config-2014-naga.sock.
+Commands: status, mappings, switch_profile, reload, pause, resume, stop.";
+
+fn main() {
+    if let Err(e) = dispatch() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn dispatch() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, args) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => ("run", &args[..]),
+    };
+
+    match command {
+        "-h" | "--help" | "help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        "-V" | "--version" => {
+            println!("{} {}", NAME, VERSION);
+            Ok(())
+        }
+        "run" => run(args),
+        "check" => check(args),
+        "list-keys" => list_keys(args),
+        "list-devices" => list_devices(args),
+        "dump-config" => dump_config(args),
+        "monitor" => monitor(args),
+        "profile" => switch_profile(args),
+        "control" => send_control(args),
+        command if command.starts_with('-') => {
+            Err(format!("Unknown option: {}\n\n{}", command, USAGE).into())
+        }
+        // The original `config-2014-naga [config.toml [profile]]` form
+        _ => match env::args().skip(1).collect::<Vec<_>>().as_slice() {
+            [config] => run(&["--config".to_string(), config.clone()]),
+            [config, profile] => run(&[
+                "--config".to_string(),
+                config.clone(),
+                "--profile".to_string(),
+                profile.clone(),
+            ]),
+            _ => Err(format!("Unknown command: {}\n\n{}", command, USAGE).into()),
         },
-        1 => (KeyMapper::default(), "default".to_string()),
-        _ => return Err("Too many arguments".into()),
+    }
+}
+
+/// Splits a command's arguments into `--name value` options, `--name`
+/// flags and positional arguments, rejecting anything not listed.
+struct Parsed {
+    options: Vec<(&'static str, String)>,
+    flags: Vec<&'static str>,
+    positional: Vec<String>,
+}
+
+impl Parsed {
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(option, _)| *option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+}
+
+/// Returns `None` when `--help` was given, after printing `usage`.
+fn parse(
+    args: &[String],
+    usage: &str,
+    options: &[&'static str],
+    flags: &[&'static str],
+    max_positional: usize,
+) -> Result<Option<Parsed>, Box<dyn Error>> {
+    let mut parsed = Parsed {
+        options: Vec::new(),
+        flags: Vec::new(),
+        positional: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            println!("{}", usage);
+            return Ok(None);
+        }
+        if let Some(&option) = options.iter().find(|option| *option == arg) {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value\n\n{}", option, usage))?;
+            parsed.options.push((option, value.clone()));
+        } else if let Some(&flag) = flags.iter().find(|flag| *flag == arg) {
+            parsed.flags.push(flag);
+        } else if arg.starts_with('-') {
+            return Err(format!("Unknown option: {}\n\n{}", arg, usage).into());
+        } else if parsed.positional.len() < max_positional {
+            parsed.positional.push(arg.clone());
+        } else {
+            return Err(format!("Too many arguments\n\n{}", usage).into());
+        }
+    }
+    Ok(Some(parsed))
+}
+
+/// Runs the remapper until stopped.
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, RUN_USAGE, &["--config", "--profile"], &[], 0)? else {
+        return Ok(());
+    };
+    println!("{}-v{}", NAME, VERSION);
+
+    let config_path = args.option("--config");
+    let mut key_mapper = match config_path {
+        Some(path) => KeyMapper::read_from_file(path)?,
+        None => KeyMapper::default(),
     };
+    if let Some(profile) = args.option("--profile") {
+        key_mapper.set_profile(profile)?;
+    }
 
-    println!("Configuration loaded from: {}", config_source);
+    match config_path {
+        Some(path) => println!("Configuration loaded from: file: {}", path),
+        None => println!("Configuration loaded from: default"),
+    }
+    if key_mapper.profile_names().len() > 1 {
+        println!("Active profile: {}", key_mapper.profile().name());
+    }
     debug_println!("\nKey mappings:");
     debug_println!("{}", key_mapper.debug_mappings());
 
+    // Changing the focus source or control socket needs a restart; rules
+    // are reloaded
+    let focus_settings = key_mapper.focus().cloned();
+    let control_settings = key_mapper.control().clone();
+
+    let key_mapper = Arc::new(reload::SharedKeyMapper::new(key_mapper));
+    let running = Arc::new(AtomicBool::new(true));
+    // Profile signals work without a config; only the file watch needs one
+    reload::watch(config_path, key_mapper.clone(), running.clone())?;
+    if let Some(settings) = focus_settings {
+        let connect = move || focus::source_from_settings(&settings);
+        focus::watch(connect, key_mapper.clone(), running.clone())?;
+    }
+    let mut control = None;
+    if control_settings.enabled() {
+        let served = control::serve(
+            &control_settings,
+            key_mapper.clone(),
+            running.clone(),
+            config_path,
+        );
+        match served {
+            Ok(handle) => control = Some(handle),
+            Err(e) => eprintln!("Control socket {} unavailable: {}", control_settings.socket(), e),
+        }
+    }
+
     // Run indefinitely
-    run_loop_blocking(key_mapper)
+    let result = run_loop_shared(key_mapper, running.clone());
+
+    // Let the control thread remove its socket
+    running.store(false, Ordering::SeqCst);
+    if let Some(control) = control {
+        let _ = control.join();
+    }
+    result?;
+    Ok(())
+}
+
+/// Validates a config without touching any device.
+fn check(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, CHECK_USAGE, &[], &[], 1)? else {
+        return Ok(());
+    };
+    let path = args
+        .positional
+        .first()
+        .ok_or_else(|| format!("No config file given\n\n{}", CHECK_USAGE))?;
+
+    let key_mapper = match KeyMapper::read_from_file(path) {
+        Ok(key_mapper) => key_mapper,
+        Err(NagaError::Config(e)) => {
+            eprintln!("{}", e);
+            return Err(format!("{} problem(s) found in {}", e.errors.len(), path).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    println!(
+        "{}: OK ({} profile(s): {})",
+        path,
+        key_mapper.profile_names().len(),
+        key_mapper.profile_names().join(", ")
+    );
+    Ok(())
+}
+
+/// Prints every accepted key name.
+fn list_keys(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, LIST_KEYS_USAGE, &[], &["--all"], 0)? else {
+        return Ok(());
+    };
+
+    let mut names = InputKey::names();
+    names.extend(InputKeyPad::names());
+    names.extend(InputCode::friendly_names());
+    names.extend(InputMouse::names());
+    names.extend(InputPad::names());
+    if args.flag("--all") {
+        names.extend(key_codes::KEY_CODES.iter().map(|(name, _)| name.to_string()));
+    }
+
+    // Usually piped into grep or head, which may stop reading early
+    let mut out = io::stdout().lock();
+    for name in names {
+        match writeln!(out, "{}", name) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Prints the input devices, marking the one the mapper would grab.
+fn list_devices(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, LIST_DEVICES_USAGE, &["--config"], &[], 0)? else {
+        return Ok(());
+    };
+    let key_mapper = match args.option("--config") {
+        Some(path) => KeyMapper::read_from_file(path)?,
+        None => KeyMapper::default(),
+    };
+
+    let devices = naga::list_devices(key_mapper.device())?;
+    for device in &devices {
+        let marker = if device.matches { "  <- side buttons" } else { "" };
+        println!(
+            "{}\t{:04x}:{:04x}\t{}\t{}{}",
+            device.path.display(),
+            device.vendor,
+            device.product,
+            device.name,
+            device.phys,
+            marker
+        );
+    }
+
+    if !devices.is_empty() {
+        println!();
+    }
+    println!("Presets for [device]:");
+    for preset in device::PRESETS {
+        println!("  {:18}{}", preset.name, preset.description);
+    }
+
+    if devices.is_empty() {
+        return Err("No input devices could be opened (try running as root)".into());
+    }
+    Ok(())
+}
+
+/// Prints the effective config.
+fn dump_config(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, DUMP_CONFIG_USAGE, &[], &[], 1)? else {
+        return Ok(());
+    };
+
+    let key_mapper = match args.positional.first() {
+        Some(path) => KeyMapper::read_from_file(path)?,
+        None => KeyMapper::default(),
+    };
+    print!("{}", key_mapper.to_toml());
+    Ok(())
+}
+
+/// Prints the Naga's events as they arrive, without remapping them.
+fn monitor(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, MONITOR_USAGE, &["--config"], &["--pointer"], 0)? else {
+        return Ok(());
+    };
+    let key_mapper = match args.option("--config") {
+        Some(path) => KeyMapper::read_from_file(path)?,
+        None => KeyMapper::default(),
+    };
+    let settings = key_mapper.device();
+
+    let naga = naga::Naga::new(settings, args.flag("--pointer"))?;
+    println!("Monitoring the Naga, press Ctrl+C to stop");
+    loop {
+        let (interface, event) = match naga.next_event() {
+            Ok(event) => event,
+            Err(e) if e.is_would_block() => {
+                naga.wait(None)?;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let prefix = match interface {
+            Interface::SideButtons => "",
+            Interface::Pointer => "Pointer: ",
+        };
+        match event.event_code() {
+            Some(EventCode::EV_SYN(_)) => {}
+            Some(EventCode::EV_KEY(key)) => {
+                let name = format!("{:?}", key);
+                let index = match interface {
+                    Interface::SideButtons => settings.button_index(event.code),
+                    Interface::Pointer => None,
+                };
+                let state = match event.value {
+                    0 => "released",
+                    1 => "pressed",
+                    _ => "repeat",
+                };
+                match index {
+                    Some(index) => println!("{}Button {} {} ({})", prefix, index + 1, state, name),
+                    None => println!("{}{} {}", prefix, name, state),
+                }
+            }
+            Some(code) => println!("{}{:?} value {}", prefix, code, event.value),
+            None => println!("{}type {} code {} value {}", prefix, event.kind, event.code, event.value),
+        }
+    }
+}
+
+/// Asks the running daemon to switch profiles.
+fn switch_profile(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, PROFILE_USAGE, &["--socket", "--config"], &[], 1)? else {
+        return Ok(());
+    };
+    let switch = match args.positional.first().map(String::as_str) {
+        Some(switch @ ("next" | "previous")) => switch,
+        _ => return Err(format!("Use next or previous\n\n{}", PROFILE_USAGE).into()),
+    };
+    let request = json!({ "command": "switch_profile", "profile": switch });
+    let reply = control_request(&control_socket(&args)?, &request.to_string())?;
+    if let Some(profile) = reply.get("profile").and_then(Value::as_str) {
+        println!("Active profile: {}", profile);
+    }
+    Ok(())
+}
+
+/// The control socket to talk to: `--socket`, the one the `--config` file
+/// sets, or the default.
+fn control_socket(args: &Parsed) -> Result<String, Box<dyn Error>> {
+    if let Some(socket) = args.option("--socket") {
+        return Ok(socket.to_string());
+    }
+    let key_mapper = match args.option("--config") {
+        Some(path) => KeyMapper::read_from_file(path)?,
+        None => KeyMapper::default(),
+    };
+    Ok(key_mapper.control().socket().to_string())
+}
+
+/// Sends a request to the daemon and returns the reply, failing with the
+/// daemon's error message if it has `"ok": false`.
+fn control_request(socket: &str, request: &str) -> Result<Value, Box<dyn Error>> {
+    let reply = control::request(socket, request).map_err(|e| {
+        format!("Could not reach {} (is the daemon running with [control] enabled?): {}", socket, e)
+    })?;
+    let reply: Value = serde_json::from_str(&reply).map_err(|e| format!("Bad reply from the daemon: {}", e))?;
+    if reply["ok"] != true {
+        let message = reply["error"].as_str().unwrap_or("Command failed");
+        return Err(message.into());
+    }
+    Ok(reply)
+}
+
+/// Sends a command to the running daemon's control socket and prints the
+/// reply, e.g. `control status` or `control switch_profile wow`. A single
+/// argument starting with `{` is sent as is.
+fn send_control(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let Some(args) = parse(args, CONTROL_USAGE, &["--socket", "--config"], &[], 2)? else {
+        return Ok(());
+    };
+    let request = match args.positional.as_slice() {
+        [json] if json.starts_with('{') => json.clone(),
+        [command] => json!({ "command": command }).to_string(),
+        [command, profile] => json!({ "command": command, "profile": profile }).to_string(),
+        _ => return Err(format!("No command given\n\n{}", CONTROL_USAGE).into()),
+    };
+    let reply = control_request(&control_socket(&args)?, &request)?;
+    println!("{}", reply);
+    Ok(())
 }
@@ -1,18 +1,152 @@
-use evdev_rs::{Device, GrabMode, InputEvent, ReadStatus, ReadFlag};
-use std::fs::{read_dir, File};
-use std::error::Error;
-use std::os::unix::io::AsRawFd;
+use crate::device::{DeviceIdentity, DeviceSettings};
+use crate::error::{self, Error};
+use crate::poll;
+use evdev_rs::enums::{int_to_event_type, EventCode, EventType, EV_KEY, EV_REL};
+use evdev_rs::util::int_to_event_code;
+use evdev_rs::{Device, GrabMode};
+use std::fs::{read_dir, File, ReadDir};
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-pub struct Naga {
+const INPUT_DIR: &str = "/dev/input";
+
+/// An input device found under `/dev/input`.
+pub struct InputDeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub phys: String,
+    pub vendor: u16,
+    pub product: u16,
+    /// Whether this is the interface the mapper would grab.
+    pub matches: bool,
+}
+
+/// Lists the event devices we are allowed to open, sorted by path, and
+/// checks each against `settings`.
+pub fn list_devices(settings: &DeviceSettings) -> Result<Vec<InputDeviceInfo>, Error> {
+    let paths = match input_dir() {
+        Ok(paths) => paths,
+        Err(Error::DeviceNotFound) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut devices = Vec::new();
+    for path in paths.flatten() {
+        if !path.file_name().to_string_lossy().starts_with("event") {
+            continue;
+        }
+        let Ok(file) = File::open(path.path()) else {
+            continue;
+        };
+        let Ok(device) = Device::new_from_fd(file) else {
+            continue;
+        };
+        devices.push(InputDeviceInfo {
+            path: path.path(),
+            name: device.name().unwrap_or("").to_string(),
+            phys: device.phys().unwrap_or("").to_string(),
+            vendor: device.vendor_id() as u16,
+            product: device.product_id() as u16,
+            matches: is_match(&device, &path.path(), settings),
+        });
+    }
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+fn input_dir() -> Result<ReadDir, Error> {
+    read_dir(INPUT_DIR).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::DeviceNotFound,
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied {
+            path: PathBuf::from(INPUT_DIR),
+        },
+        _ => Error::Io(e),
+    })
+}
+
+/// Whether `device` is the interface described by `settings`. It must also
+/// be able to send a button's key code, which rules out the mouse's other
+/// interfaces should the matcher be loose.
+fn is_match(device: &Device, path: &Path, settings: &DeviceSettings) -> bool {
+    let identity = DeviceIdentity {
+        path,
+        name: device.name().unwrap_or(""),
+        phys: device.phys().unwrap_or(""),
+        vendor: device.vendor_id() as u16,
+        product: device.product_id() as u16,
+    };
+    settings.matcher().matches(&identity)
+        && settings.buttons().iter().any(|&code| {
+            int_to_event_code(EventType::EV_KEY as u32, code as u32)
+                .is_some_and(|code| device.has(&code))
+        })
+}
+
+/// Whether `device` is the pointer interface described by `settings`,
+/// which moves the cursor and has the main buttons.
+fn is_pointer(device: &Device, path: &Path, settings: &DeviceSettings) -> bool {
+    let identity = DeviceIdentity {
+        path,
+        name: device.name().unwrap_or(""),
+        phys: device.phys().unwrap_or(""),
+        vendor: device.vendor_id() as u16,
+        product: device.product_id() as u16,
+    };
+    settings.pointer_matcher().matches(&identity)
+        && device.has(&EventCode::EV_REL(EV_REL::REL_X))
+        && device.has(&EventCode::EV_KEY(EV_KEY::BTN_LEFT))
+}
+
+/// Which of the mouse's interfaces an event came from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interface {
+    /// The keyboard-like interface with the side buttons.
+    SideButtons,
+    /// The interface with the main buttons, the wheel and motion.
+    Pointer,
+}
+
+/// An input event as the kernel sent it.
+///
+/// Events are read without going through evdev-rs, which panics on codes
+/// it doesn't know such as `REL_WHEEL_HI_RES`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    /// Kernel timestamp, on the monotonic clock.
+    pub time: Duration,
+    /// The event type, `EV_KEY`, `EV_REL`, ...
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl Event {
+    /// The event's type and code as evdev-rs names them, if it knows them.
+    pub fn event_code(&self) -> Option<EventCode> {
+        int_to_event_type(self.kind as u32)?;
+        int_to_event_code(self.kind as u32, self.code as u32)
+    }
+}
+
+/// One grabbed event device.
+struct Grabbed {
     device: Device,
     // need to keep this file, otherwise file would be closed too early
-    _file: File,
+    file: File,
 }
 
-impl Naga {
-    pub fn new() -> Result<Naga, Box<dyn Error>> {
-        let paths = read_dir("/dev/input")
-            .map_err(|e| format!("Problem reading input devices dir: {}", e))?;
+impl Grabbed {
+    /// Grabs the first event device for which `wanted` is true.
+    ///
+    /// Fails with [`Error::PermissionDenied`] rather than
+    /// [`Error::DeviceNotFound`] if some event device could not be opened,
+    /// since the wanted one may be among them.
+    fn find(wanted: impl Fn(&Device, &Path) -> bool) -> Result<Grabbed, Error> {
+        let paths = input_dir()?;
+        let mut denied = None;
 
         for path_result in paths {
             let path = match path_result {
@@ -32,7 +166,12 @@ impl Naga {
             // Try to open the file, skip if we can't
             let file = match File::open(path.path()) {
                 Ok(f) => f,
-                Err(_) => continue,
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::PermissionDenied {
+                        denied.get_or_insert(path.path());
+                    }
+                    continue;
+                }
             };
             
             let file_clone = match file.try_clone() {
@@ -46,12 +185,16 @@ impl Naga {
                 Err(_) => continue,
             };
 
-            if device.name().unwrap_or("").eq("Razer Razer Naga 2014")
-                && device.phys().unwrap_or("").ends_with("/input2")
-            {
+            if wanted(&device, &path.path()) {
                 device
                     .grab(GrabMode::Grab)
-                    .map_err(|e| format!("Could not grab device: {}", e))?;
+                    .map_err(|e| Error::GrabFailed(error::from_errno(e as i32)))?;
+
+                // Timestamp events on the monotonic clock so they can be
+                // compared against timers in the event mapper
+                device
+                    .set_clock_id(libc::CLOCK_MONOTONIC)
+                    .map_err(|e| Error::Io(error::from_errno(e as i32)))?;
                 
                 // Set the device to non-blocking mode
                 let fd = file_clone.as_raw_fd();
@@ -60,20 +203,133 @@ impl Naga {
                     libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
                 }
                 
-                return Ok(Naga {
+                return Ok(Grabbed {
                     device,
-                    _file: file_clone,
+                    file: file_clone,
                 });
             }
         }
 
-        Err("No device found".to_string())?
+        match denied {
+            Some(path) => Err(Error::PermissionDenied { path }),
+            None => Err(Error::DeviceNotFound),
+        }
+    }
+
+    fn set_grabbed(&mut self, grabbed: bool) -> Result<(), Error> {
+        let mode = if grabbed { GrabMode::Grab } else { GrabMode::Ungrab };
+        self.device.grab(mode).map_err(|e| match error::from_errno(e as i32) {
+            e if is_removed(&e) => Error::DeviceRemoved,
+            e => Error::GrabFailed(e),
+        })
+    }
+
+    fn next_event(&self) -> Result<Event, Error> {
+        let mut event: libc::input_event = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::input_event>();
+        let read = unsafe {
+            libc::read(
+                self.file.as_raw_fd(),
+                &mut event as *mut libc::input_event as *mut libc::c_void,
+                size,
+            )
+        };
+        if read < 0 {
+            return Err(match io::Error::last_os_error() {
+                e if is_removed(&e) => Error::DeviceRemoved,
+                e => Error::Io(e),
+            });
+        }
+        // evdev only ever hands out whole events
+        if read as usize != size {
+            return Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        Ok(Event {
+            time: Duration::new(event.time.tv_sec as u64, event.time.tv_usec as u32 * 1000),
+            kind: event.type_,
+            code: event.code,
+            value: event.value,
+        })
+    }
+}
+
+pub struct Naga {
+    side_buttons: Grabbed,
+    // Only grabbed when the config remaps the main buttons or the wheel
+    pointer: Option<Grabbed>,
+}
+
+impl Naga {
+    /// Finds and grabs the side button interface described by `settings`,
+    /// and with `pointer` the pointer interface too.
+    ///
+    /// Fails with [`Error::PermissionDenied`] rather than
+    /// [`Error::DeviceNotFound`] if some event device could not be opened,
+    /// since the Naga may be among them. A pointer interface that can't be
+    /// found is only reported, leaving the main buttons alone.
+    pub fn new(settings: &DeviceSettings, pointer: bool) -> Result<Naga, Error> {
+        let side_buttons = Grabbed::find(|device, path| is_match(device, path, settings))?;
+        let pointer = match pointer {
+            true => match Grabbed::find(|device, path| is_pointer(device, path, settings)) {
+                Ok(pointer) => Some(pointer),
+                Err(Error::DeviceNotFound) => {
+                    eprintln!("Pointer interface not found, main buttons and wheel not remapped");
+                    None
+                }
+                Err(e @ Error::PermissionDenied { .. }) => {
+                    eprintln!("Main buttons and wheel not remapped: {}", e);
+                    None
+                }
+                Err(e) => return Err(e),
+            },
+            false => None,
+        };
+        Ok(Naga { side_buttons, pointer })
+    }
+
+    /// Whether the pointer interface was grabbed as well.
+    pub fn has_pointer(&self) -> bool {
+        self.pointer.is_some()
+    }
+
+    /// Grabs the mouse so only we see its events, or lets go of it so its
+    /// buttons behave like an ordinary mouse again.
+    pub fn set_grabbed(&mut self, grabbed: bool) -> Result<(), Error> {
+        self.side_buttons.set_grabbed(grabbed)?;
+        if let Some(pointer) = &mut self.pointer {
+            pointer.set_grabbed(grabbed)?;
+        }
+        Ok(())
     }
 
-    pub fn next_event(&self) -> Result<(ReadStatus, InputEvent), String> {
-        match self.device.next_event(ReadFlag::NORMAL) {
-            Ok(res) => Ok(res),
-            Err(errno) => Err(format!("Problem reading event: {}", errno)),
+    /// Reads the next event from either interface. With no event waiting
+    /// this fails with an error for which [`Error::is_would_block`] is
+    /// true, and once the mouse is unplugged with [`Error::DeviceRemoved`].
+    pub fn next_event(&self) -> Result<(Interface, Event), Error> {
+        match (self.side_buttons.next_event(), &self.pointer) {
+            (Err(e), Some(pointer)) if e.is_would_block() => {
+                pointer.next_event().map(|event| (Interface::Pointer, event))
+            }
+            (result, _) => result.map(|event| (Interface::SideButtons, event)),
         }
     }
+
+    /// The fds of the grabbed interfaces, for waiting until one has events.
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.side_buttons.file.as_raw_fd()];
+        fds.extend(self.pointer.iter().map(|pointer| pointer.file.as_raw_fd()));
+        fds
+    }
+
+    /// Blocks until either interface has events, or has gone away, or
+    /// `timeout` passes. Without a timeout it may block indefinitely.
+    pub fn wait(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        poll::wait(&self.fds(), None, timeout)?;
+        Ok(())
+    }
+}
+
+/// Whether an error means the device node has gone away.
+fn is_removed(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENODEV)
 }
@@ -0,0 +1,123 @@
+//! Sleeping until there is something to do.
+//!
+//! The event loop blocks in `poll` on the Naga's fds and a [`Waker`], so it
+//! uses no CPU while the mouse is idle and still reacts at once to input,
+//! reloads, profile switches and shutdown.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+/// Longest time to wait before checking a `running` flag, for callers that
+/// clear it without waking the loop.
+pub(crate) const RUNNING_CHECK: Duration = Duration::from_secs(1);
+
+/// An eventfd that wakes up a thread blocked in [`wait`].
+pub(crate) struct Waker {
+    fd: RawFd,
+}
+
+impl Waker {
+    pub(crate) fn new() -> io::Result<Waker> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Waker { fd })
+    }
+
+    /// Makes the current or next [`wait`] on this waker return.
+    pub(crate) fn wake(&self) {
+        let one: u64 = 1;
+        // Only fails if the counter would overflow, when a wake is pending anyway
+        unsafe {
+            libc::write(self.fd, &one as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    fn reset(&self) {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(self.fd, &mut count as *mut u64 as *mut libc::c_void, 8);
+        }
+    }
+}
+
+impl Drop for Waker {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Blocks until one of `fds` is readable (or has hung up), `waker` is
+/// woken, or `timeout` passes. Without a timeout it may block indefinitely.
+///
+/// A signal interrupting the wait counts as a wake-up.
+pub(crate) fn wait(fds: &[RawFd], waker: Option<&Waker>, timeout: Option<Duration>) -> io::Result<()> {
+    let mut pollfds = Vec::with_capacity(fds.len() + 1);
+    for &fd in fds {
+        pollfds.push(libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+    if let Some(waker) = waker {
+        pollfds.push(libc::pollfd {
+            fd: waker.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        });
+    }
+
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    });
+    let timeout_ptr = timeout
+        .as_ref()
+        .map_or(std::ptr::null(), |timeout| timeout as *const libc::timespec);
+
+    let ready = unsafe {
+        libc::ppoll(
+            pollfds.as_mut_ptr(),
+            pollfds.len() as libc::nfds_t,
+            timeout_ptr,
+            std::ptr::null(),
+        )
+    };
+    if ready < 0 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+
+    if let Some(waker) = waker {
+        waker.reset();
+    }
+    Ok(())
+}
+
+/// Waits up to `timeout` for `fd` to become readable (or hang up), and
+/// says whether it did. A signal interrupting the wait counts as a timeout.
+pub(crate) fn readable(fd: RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+        ready if ready >= 0 => Ok(pollfd.revents != 0),
+        _ => {
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(e),
+            }
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Reloading the config file and switching profiles while the daemon is
+//! running.
+//!
+//! The active mapping lives in a [`SharedKeyMapper`]. A watcher thread
+//! re-reads the config file when it changes on disk or the process gets
+//! `SIGHUP`, and swaps the new mapping in only if it parses. `SIGUSR1` and
+//! `SIGUSR2` switch to the next and previous profile. The event loop picks
+//! the new mapping up between events.
+
+use crate::device::DeviceSettings;
+use crate::exec;
+use crate::input_device::VirtualDeviceSettings;
+use crate::key_map::{KeyMapper, ProfileSwitch};
+use crate::poll::Waker;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long the watcher waits for an event before checking for `SIGHUP`
+/// and the running flag again.
+const POLL_INTERVAL_MS: libc::c_int = 250;
+
+/// Editors often save in several steps; wait this long after a change for
+/// the file to settle before reading it.
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+// Set by the signal handlers, cleared by the watcher
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static NEXT_PROFILE: AtomicBool = AtomicBool::new(false);
+static PREVIOUS_PROFILE: AtomicBool = AtomicBool::new(false);
+
+/// The mapping currently in effect, shared between the event loop and
+/// whatever replaces it at runtime.
+pub struct SharedKeyMapper {
+    key_mapper: Mutex<KeyMapper>,
+    // Bumped on every change so readers can tell cheaply that it changed
+    generation: AtomicU64,
+    // Bumped only when the whole mapping is replaced
+    reloads: AtomicU64,
+    // While set, the mouse is released and nothing is remapped
+    paused: AtomicBool,
+    // Wakes the event loop when any of the above changes; without one the
+    // loop falls back to checking periodically
+    waker: Option<Waker>,
+}
+
+/// Identifies one state of a [`SharedKeyMapper`].
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Generation {
+    /// Changes on every reload and profile switch.
+    pub changes: u64,
+    /// Changes only on reloads.
+    pub reloads: u64,
+}
+
+impl SharedKeyMapper {
+    pub fn new(key_mapper: KeyMapper) -> SharedKeyMapper {
+        SharedKeyMapper {
+            key_mapper: Mutex::new(key_mapper),
+            generation: AtomicU64::new(0),
+            reloads: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            waker: Waker::new().ok(),
+        }
+    }
+
+    /// Wakes the event loop following this mapping so it acts on a change
+    /// straight away, e.g. after its `running` flag has been cleared.
+    pub fn wake(&self) {
+        if let Some(waker) = &self.waker {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn waker(&self) -> Option<&Waker> {
+        self.waker.as_ref()
+    }
+
+    /// Pauses or resumes remapping. While paused the mouse is ungrabbed,
+    /// so its buttons send their own keys.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+        self.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Makes `key_mapper` the active mapping, staying on the current
+    /// profile if the new mapping still has it.
+    pub fn replace(&self, mut key_mapper: KeyMapper) {
+        let mut current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = key_mapper.set_profile(current.profile().name());
+        *current = key_mapper;
+        self.reloads.fetch_add(1, Ordering::SeqCst);
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Switches the active profile, logging the change and running the
+    /// `on_profile_switch` hook.
+    pub fn switch_profile(&self, switch: &ProfileSwitch) -> Result<(), String> {
+        let mut current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        if !current.switch_profile(switch)? {
+            return Ok(());
+        }
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.wake();
+
+        let name = current.profile().name();
+        println!("Switched to profile: {}", name);
+        if let Some(hook) = &current.on_profile_switch {
+            let mut hook = hook.clone();
+            hook.env.insert("NAGA_PROFILE".to_string(), name.to_string());
+            if let Err(e) = exec::spawn(&hook) {
+                eprintln!("Could not run {}: {}", hook.program, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the active mapping along with its generation.
+    pub fn current(&self) -> (Generation, KeyMapper) {
+        let current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = Generation {
+            changes: self.generation.load(Ordering::SeqCst),
+            reloads: self.reloads.load(Ordering::SeqCst),
+        };
+        (generation, current.clone())
+    }
+
+    /// The device the active mapping remaps.
+    pub fn device(&self) -> DeviceSettings {
+        let current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        current.device().clone()
+    }
+
+    /// How the active mapping's virtual keyboard presents itself.
+    pub fn virtual_device(&self) -> VirtualDeviceSettings {
+        let current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        current.virtual_device().clone()
+    }
+
+    /// Whether the active mapping remaps the pointer interface.
+    pub fn grabs_pointer(&self) -> bool {
+        let current = self.key_mapper.lock().unwrap_or_else(|e| e.into_inner());
+        current.grabs_pointer()
+    }
+
+    /// Changes every time the mapping is replaced or switches profile.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+}
+
+/// Starts a thread that switches profiles on `SIGUSR1` and `SIGUSR2` and,
+/// given a config `path`, reloads it into `key_mapper` whenever the file
+/// changes or the process receives `SIGHUP`. The signal handlers are
+/// installed either way, so that the signals never kill the daemon. The
+/// thread exits once `running` is cleared.
+pub fn watch(
+    path: Option<&str>,
+    key_mapper: Arc<SharedKeyMapper>,
+    running: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    install_handler(libc::SIGHUP, on_hangup)?;
+    install_handler(libc::SIGUSR1, on_next_profile)?;
+    install_handler(libc::SIGUSR2, on_previous_profile)?;
+
+    let path = path.map(PathBuf::from);
+    let watcher = path.as_ref().and_then(|path| match Watcher::new(path) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("Not watching {} for changes ({}), reload with SIGHUP", path.display(), e);
+            None
+        }
+    });
+
+    thread::Builder::new()
+        .name("config-reload".to_string())
+        .spawn(move || {
+            while running.load(Ordering::SeqCst) {
+                let changed = match &watcher {
+                    Some(watcher) => watcher.wait(POLL_INTERVAL_MS),
+                    None => {
+                        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS as u64));
+                        false
+                    }
+                };
+                if changed {
+                    thread::sleep(SETTLE_TIME);
+                    if let Some(watcher) = &watcher {
+                        watcher.wait(0);
+                    }
+                }
+
+                // Without a config there is nothing to reload
+                let reload_wanted = HANGUP.swap(false, Ordering::SeqCst) || changed;
+                match &path {
+                    Some(path) if reload_wanted && running.load(Ordering::SeqCst) => {
+                        let _ = reload(path, &key_mapper);
+                    }
+                    _ => {}
+                }
+
+                let switch = if NEXT_PROFILE.swap(false, Ordering::SeqCst) {
+                    Some(ProfileSwitch::Next)
+                } else if PREVIOUS_PROFILE.swap(false, Ordering::SeqCst) {
+                    Some(ProfileSwitch::Previous)
+                } else {
+                    None
+                };
+                if let Some(switch) = switch {
+                    if let Err(e) = key_mapper.switch_profile(&switch) {
+                        eprintln!("Could not switch profile: {}", e);
+                    }
+                }
+            }
+        })
+}
+
+/// Re-reads the config, keeping the current mapping if the new one is bad.
+pub fn reload(path: &Path, key_mapper: &SharedKeyMapper) -> Result<(), String> {
+    match KeyMapper::read_from_file(&path.to_string_lossy()) {
+        Ok(new) => {
+            key_mapper.replace(new);
+            println!("Configuration reloaded from: {}", path.display());
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!(
+                "Error reloading {}, keeping the previous mapping: {}",
+                path.display(),
+                e
+            );
+            Err(e.to_string())
+        }
+    }
+}
+
+extern "C" fn on_hangup(_signal: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_next_profile(_signal: libc::c_int) {
+    NEXT_PROFILE.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_previous_profile(_signal: libc::c_int) {
+    PREVIOUS_PROFILE.store(true, Ordering::SeqCst);
+}
+
+fn install_handler(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) -> io::Result<()> {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// An inotify watch on the config file's directory.
+///
+/// The directory is watched rather than the file itself because many
+/// editors save by writing a new file and renaming it over the old one.
+struct Watcher {
+    fd: libc::c_int,
+    file_name: Vec<u8>,
+}
+
+impl Watcher {
+    fn new(path: &Path) -> io::Result<Watcher> {
+        let file_name = path
+            .file_name()
+            .map(|name| name.as_bytes().to_vec())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let watcher = Watcher { fd, file_name };
+
+        let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(watcher)
+    }
+
+    /// Waits up to `timeout_ms` for events and reports whether any of them
+    /// were for the config file. Always drains every pending event.
+    fn wait(&self, timeout_ms: libc::c_int) -> bool {
+        let mut pollfd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } <= 0 {
+            return false;
+        }
+
+        let mut changed = false;
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                return changed;
+            }
+
+            let header = std::mem::size_of::<libc::inotify_event>();
+            let mut offset = 0;
+            while offset + header <= len as usize {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name = &buf[offset + header..offset + header + event.len as usize];
+                // The name is padded with NULs up to `len`
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                if name == self.file_name.as_slice() {
+                    changed = true;
+                }
+                offset += header + event.len as usize;
+            }
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}